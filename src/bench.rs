@@ -0,0 +1,110 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::AtomicBool},
+    time::Instant,
+};
+
+use anyhow::anyhow;
+use indicatif::HumanBytes;
+
+use crate::{
+    AppData,
+    download::{create_download_contexts, download, generate_buckets},
+    models::{Args, DropManifest},
+    report::DownloadStats,
+};
+
+// Extra threads past what the link can use only add contention, so anything this close to the fastest wins by being smaller
+const RECOMMEND_MARGIN: f64 = 0.05;
+
+// Removes the scratch dir however the benchmark ends, so a failed run doesn't leave part of the sample behind
+struct ScratchDir(PathBuf);
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.0)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            eprintln!("failed to remove {}: {e}", self.0.display());
+        }
+    }
+}
+
+// Downloads the same sample of the game once per thread count, into a scratch dir in the install dir that's removed afterwards
+pub fn bench(game_id: &str, manifest: &DropManifest, budget: u64, thread_counts: &[usize], app_data: &AppData, args: &mut Args) -> Result<(), anyhow::Error> {
+    // Every run would be a single thread, whatever the count
+    if args.serial {
+        return Err(anyhow!("--serial can't be used with bench, since it runs every thread count with one thread"));
+    }
+    let budget = budget as usize;
+
+    // Sorted so the same budget samples the same files every time
+    let mut paths = manifest.keys().collect::<Vec<_>>();
+    paths.sort();
+    let mut sample_size = 0;
+    let sample = paths
+        .into_iter()
+        .take_while(|path| {
+            let fits = sample_size < budget;
+            sample_size += manifest[*path].lengths.iter().sum::<usize>();
+            fits
+        })
+        .map(|path| (path.clone(), manifest[path].clone()))
+        .collect::<DropManifest>();
+
+    let dir = ScratchDir(Path::new(&args.install_dir).join(".bucket-bench"));
+    let download_contexts = create_download_contexts(game_id, &sample, app_data, &args.state_file, None)?;
+    let mut buckets = generate_buckets(game_id.to_owned(), &dir.0.to_string_lossy(), &sample, &download_contexts, args.dir_mode)?;
+
+    // A single large file can take the sample well past the budget, so buckets are trimmed to it too
+    let mut bucket_size = 0;
+    buckets.retain(|bucket| {
+        let fits = bucket_size < budget;
+        bucket_size += bucket.drops.iter().map(|drop| drop.length).sum::<usize>();
+        fits
+    });
+    let bucket_size = buckets.iter().flat_map(|bucket| &bucket.drops).map(|drop| drop.length).sum::<usize>();
+    println!("benchmarking with {} buckets, {}", buckets.len(), HumanBytes(bucket_size as u64));
+
+    let mut results = Vec::new();
+    for &threads in thread_counts {
+        // Starting every run from nothing, so none of them get a head start from the last one's files
+        for drop in buckets.iter().flat_map(|bucket| &bucket.drops) {
+            if let Err(e) = fs::remove_file(&drop.path)
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                return Err(e.into());
+            }
+        }
+
+        args.threads = threads;
+        let mut stats = DownloadStats::default();
+        let started = Instant::now();
+        let result = download(game_id.to_owned(), buckets.clone(), &download_contexts, app_data, args, Arc::new(AtomicBool::new(false)), &mut stats);
+        let elapsed = started.elapsed().as_secs_f64();
+
+        match result {
+            Ok(()) => {
+                let speed = stats.downloaded_bytes as f64 / elapsed;
+                results.push((threads, speed));
+            }
+            Err(e) => println!("{threads} threads failed: {e:#}"),
+        }
+    }
+
+    drop(dir);
+
+    println!();
+    for (threads, speed) in &results {
+        println!("{threads:>4} threads: {}/s", HumanBytes(*speed as u64));
+    }
+
+    let fastest = results.iter().map(|(_, speed)| *speed).fold(0.0, f64::max);
+    match results.iter().filter(|(_, speed)| *speed >= fastest * (1.0 - RECOMMEND_MARGIN)).map(|(threads, _)| *threads).min() {
+        Some(threads) => println!("recommended: --threads {threads}"),
+        None => println!("no run finished, so there's nothing to recommend"),
+    }
+
+    Ok(())
+}
@@ -1,59 +1,179 @@
 use std::{
-    collections::{HashMap, HashSet},
-    fs::create_dir_all,
-    path::Path,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    fmt::{self, Display},
+    fs::{self, File, create_dir_all},
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+    slice,
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
-    time::Instant,
+    thread,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
+use filetime::FileTime;
+use glob::Pattern;
+use indicatif::{HumanBytes, HumanDuration};
+use rand::{RngExt, SeedableRng, make_rng, rngs::StdRng};
 use rayon::ThreadPoolBuilder;
+use reqwest::StatusCode;
 
 use crate::{
     AppData, AuthData,
-    download_internals::DropDownloadPipeline,
-    generate_authorization_header,
-    models::{Args, ChunkBody, DownloadBucket, DownloadContext, DownloadDrop, DropManifest, ManifestBody},
+    download_internals::{BufferSizes, DropDownloadPipeline, Watchdog, checksum_matches, copy_drops, drop_is_valid, remove_checkpoint, resume_offset},
+    error::BucketError,
+    generate_authorization_header, http,
+    models::{Args, BucketOrder, Capability, ChunkBody, DownloadBucket, DownloadContext, DownloadDrop, DropChunk, DropManifest, ManifestBody, ManifestLock, OnFailure, SetMtime},
+    progress::{Progress, SpeedMeter},
+    report::DownloadStats,
+    state::{read_install_state, save_install_state},
 };
 
 static RETRY_COUNT: usize = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+static RESET_RETRY_COUNT: usize = 5;
+const RESET_RETRY_DELAY: Duration = Duration::from_millis(250);
+const PAUSE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const UNLOCK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+// Past this, a saved context isn't worth trying over a fresh one
+const SAVED_CONTEXT_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+static JITTER_RNG: OnceLock<Mutex<StdRng>> = OnceLock::new();
 
 const TARGET_BUCKET_SIZE: usize = 63 * 1000 * 1000;
-const MAX_FILES_PER_BUCKET: usize = (1024 / 4) - 1;
+// What servers that don't say otherwise accept per chunk request
+pub const MAX_FILES_PER_BUCKET: usize = (1024 / 4) - 1;
 
-pub fn generate_buckets(game_id: String, install_dir: &str, manifest: &DropManifest) -> Vec<DownloadBucket> {
+// Creates dir along with any missing parents, giving the ones it creates the requested mode
+fn create_dirs(dir: &Path, dir_mode: Option<u32>) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = dir_mode {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Set afterwards rather than through DirBuilder so the umask doesn't get a say
+        let missing = dir.ancestors().take_while(|dir| !dir.exists()).collect::<Vec<_>>();
+        create_dir_all(dir)?;
+        for dir in missing {
+            fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+        }
+        return Ok(());
+    }
+    #[cfg(not(unix))]
+    let _ = dir_mode;
+
+    create_dir_all(dir)
+}
+
+// Gets the install dir ready for the manifest (directories, symlinks, shrunk files) and splits it into buckets
+pub fn generate_buckets(game_id: String, install_dir: &str, manifest: &DropManifest, download_contexts: &HashMap<String, DownloadContext>, dir_mode: Option<u32>) -> Result<Vec<DownloadBucket>, BucketError> {
     let base_path = Path::new(install_dir);
-    create_dir_all(base_path).unwrap();
 
-    let mut buckets = Vec::new();
+    // Sorted, so links that lead through other links are made the same way every time
+    let mut raw_paths = manifest.keys().collect::<Vec<_>>();
+    raw_paths.sort();
+    let (links, files) = raw_paths.into_iter().partition::<Vec<_>, _>(|raw_path| manifest[*raw_path].symlink.is_some());
 
-    let mut current_buckets = HashMap::<String, DownloadBucket>::new();
-    let mut current_bucket_sizes = HashMap::<String, usize>::new();
+    // Anything underneath a link would be written wherever it points
+    let link_paths = links.iter().map(|raw_path| Path::new(raw_path.as_str())).collect::<HashSet<_>>();
+    for raw_path in files.iter().chain(&links) {
+        if let Some(link) = Path::new(raw_path.as_str()).ancestors().skip(1).find(|ancestor| link_paths.contains(ancestor)) {
+            return Err(BucketError::SymlinkParent {
+                link: base_path.join(link),
+                path: base_path.join(raw_path),
+            });
+        }
+    }
 
-    for (raw_path, chunk) in manifest {
-        let path = base_path.join(Path::new(&raw_path));
+    create_dirs(base_path, dir_mode)?;
+
+    // Directories go first, so no link is made where a directory is about to be
+    for raw_path in &files {
+        let path = base_path.join(Path::new(raw_path));
 
         let container = path.parent().unwrap();
-        create_dir_all(container).unwrap();
+        create_dirs(container, dir_mode)?;
 
-        let mut file_running_offset = 0;
+        // Drops never truncate, so a file that shrank since it was last written would keep its old tail
+        let file_size = manifest[*raw_path].lengths.iter().sum::<usize>() as u64;
+        if let Ok(metadata) = fs::metadata(&path)
+            && metadata.len() > file_size
+        {
+            fs::OpenOptions::new().write(true).open(&path)?.set_len(file_size)?;
+        }
+    }
 
-        for (index, length) in chunk.lengths.iter().enumerate() {
-            let drop = DownloadDrop {
-                filename: raw_path.to_string(),
-                start: file_running_offset,
-                length: *length,
-                checksum: chunk.checksums[index].clone(),
-                permissions: chunk.permissions,
-                path: path.clone(),
-                index,
-            };
-            file_running_offset += *length;
+    for raw_path in links {
+        let path = base_path.join(Path::new(raw_path));
+        create_dirs(path.parent().unwrap(), dir_mode)?;
+        create_symlink(base_path, &path, manifest[raw_path].symlink.as_ref().unwrap())?;
+    }
 
-            if *length >= TARGET_BUCKET_SIZE {
+    Ok(plan_buckets(game_id, install_dir, manifest, download_contexts))
+}
+
+// Empties every file for --no-resume, so nothing already on disk survives into the new download. Runs before
+// generate_buckets has checked anything, so paths are kept to the install dir here too, and symlinks are left alone
+// since they could point anywhere.
+pub fn truncate_files(install_dir: &str, manifest: &DropManifest) -> Result<(), BucketError> {
+    let base = match fs::canonicalize(install_dir) {
+        Ok(base) => base,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for (raw_path, chunk) in manifest {
+        if chunk.symlink.is_some() {
+            continue;
+        }
+        let outside = || BucketError::OutsideInstallDir {
+            path: Path::new(install_dir).join(raw_path),
+            target: None,
+        };
+        let raw_path = Path::new(raw_path);
+        let (Some(parent), Some(name)) = (raw_path.parent(), raw_path.file_name()) else {
+            return Err(outside());
+        };
+        let Some(parent) = resolve_within(&base, &base, parent)? else {
+            return Err(outside());
+        };
+
+        let path = parent.join(name);
+        match fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.is_file() => fs::OpenOptions::new().write(true).open(&path)?.set_len(0)?,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+// Splits the manifest into buckets without touching the disk, for --dry-run
+pub fn plan_buckets(game_id: String, install_dir: &str, manifest: &DropManifest, download_contexts: &HashMap<String, DownloadContext>) -> Vec<DownloadBucket> {
+    let base_path = Path::new(install_dir);
+    let mut buckets = Vec::new();
+
+    let mut current_buckets = BTreeMap::<String, DownloadBucket>::new();
+    let mut current_bucket_sizes = HashMap::<String, usize>::new();
+
+    // Sorted, so the same manifest always makes the same buckets in the same order
+    let mut raw_paths = manifest.keys().collect::<Vec<_>>();
+    raw_paths.sort();
+    for raw_path in raw_paths {
+        let chunk = &manifest[raw_path];
+        if chunk.symlink.is_some() {
+            continue;
+        }
+        let path = base_path.join(Path::new(&raw_path));
+
+        for drop in file_drops(raw_path, path, chunk) {
+            let length = drop.length;
+            if length >= TARGET_BUCKET_SIZE {
                 // They get their own bucket
 
                 buckets.push(DownloadBucket {
@@ -74,7 +194,8 @@ pub fn generate_buckets(game_id: String, install_dir: &str, manifest: &DropManif
                 drops: vec![],
             });
 
-            if (*current_bucket_size + length >= TARGET_BUCKET_SIZE || current_bucket.drops.len() >= MAX_FILES_PER_BUCKET) && !current_bucket.drops.is_empty() {
+            let max_files = download_contexts.get(&chunk.version_name).map(DownloadContext::max_files).unwrap_or(MAX_FILES_PER_BUCKET);
+            if (*current_bucket_size + length >= TARGET_BUCKET_SIZE || current_bucket.drops.len() >= max_files) && !current_bucket.drops.is_empty() {
                 // Move current bucket into list and make a new one
                 buckets.push(current_bucket.clone());
                 *current_bucket = DownloadBucket {
@@ -86,7 +207,7 @@ pub fn generate_buckets(game_id: String, install_dir: &str, manifest: &DropManif
             }
 
             current_bucket.drops.push(drop);
-            *current_bucket_size += *length;
+            *current_bucket_size += length;
         }
     }
 
@@ -96,106 +217,1424 @@ pub fn generate_buckets(game_id: String, install_dir: &str, manifest: &DropManif
         }
     }
 
-    return buckets;
+    buckets
+}
+
+// Splits a manifest entry into one drop per chunk, each written at its offset into path
+pub fn file_drops(raw_path: &str, path: PathBuf, chunk: &DropChunk) -> Vec<DownloadDrop> {
+    let mut file_running_offset = 0;
+    chunk
+        .lengths
+        .iter()
+        .enumerate()
+        .map(|(index, length)| {
+            let drop = DownloadDrop {
+                filename: raw_path.to_string(),
+                start: file_running_offset,
+                length: *length,
+                checksum: chunk.checksums[index].clone(),
+                permissions: chunk.permissions,
+                path: path.clone(),
+                index,
+                offset: 0,
+                mtime: chunk.mtime,
+            };
+            file_running_offset += *length;
+            drop
+        })
+        .collect()
+}
+
+// Sorts are stable, so buckets keep their manifest order within each group
+pub fn order_buckets(buckets: &mut [DownloadBucket], order: BucketOrder, priority: &[Pattern]) {
+    match order {
+        BucketOrder::AsIs => {}
+        BucketOrder::SmallFirst => buckets.sort_by_key(|bucket| bucket.drops.iter().map(|drop| drop.length).sum::<usize>()),
+        BucketOrder::Priority => buckets.sort_by_key(|bucket| !bucket.drops.iter().any(|drop| priority.iter().any(|pattern| pattern.matches(&drop.filename)))),
+        // Stable, so everything else keeps manifest order behind them
+        BucketOrder::LargeFirst => buckets.sort_by_key(|bucket| match bucket.drops.as_slice() {
+            [drop] if drop.length >= TARGET_BUCKET_SIZE => Reverse(drop.length),
+            _ => Reverse(0),
+        }),
+    }
+}
+
+// Walks relative from start a component at a time, following whatever is already on disk (symlinks from earlier in
+// the manifest included), and gives None if it ever leaves base, even on the way back to somewhere inside.
+// base and start have to be canonical. What doesn't exist yet is taken as it's written.
+fn resolve_within(base: &Path, start: &Path, relative: &Path) -> io::Result<Option<PathBuf>> {
+    let mut resolved = start.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => {
+                resolved.push(name);
+                match fs::canonicalize(&resolved) {
+                    Ok(canonical) => resolved = canonical,
+                    // A dangling symlink could point anywhere once something is created at its target
+                    Err(e) if e.kind() == io::ErrorKind::NotFound && fs::symlink_metadata(&resolved).is_ok() => return Ok(None),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Ok(None),
+        }
+        if !resolved.starts_with(base) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(resolved))
+}
+
+// The link's parent has to exist, which generate_buckets sees to
+fn create_symlink(base_path: &Path, path: &Path, target: &str) -> Result<(), BucketError> {
+    let outside = || BucketError::OutsideInstallDir {
+        path: path.to_path_buf(),
+        target: Some(target.to_owned()),
+    };
+    // Checked against where the link really ends up, since the parent may have come through another link
+    let base = fs::canonicalize(base_path)?;
+    let parent = fs::canonicalize(path.parent().unwrap())?;
+    if !parent.starts_with(&base) || resolve_within(&base, &parent, Path::new(target))?.is_none() {
+        return Err(outside());
+    }
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(target, path)?;
+
+    #[cfg(not(unix))]
+    println!("skipping symlink {} -> {target}, symlinks are only supported on unix", path.display());
+
+    Ok(())
+}
+
+fn create_download_context(game_id: &str, version: &str, auth: &AuthData, client: &reqwest::blocking::Client) -> Result<DownloadContext, anyhow::Error> {
+    let download_context = http::send(
+        client
+            .post(auth.endpoint("/api/v2/client/context"))
+            .json(&ManifestBody {
+                game: game_id.to_string(),
+                version: version.to_string(),
+            })
+            .header("Authorization", generate_authorization_header(auth)),
+    )?;
+
+    let status = download_context.status();
+    if status != 200 {
+        return Err(anyhow::Error::new(BucketError::ServerError { status, body: download_context.text()? }).context("failed to generate download context"));
+    }
+
+    let download_context = download_context.json::<DownloadContext>()?;
+    // Decrypting is only wired up for the scheme the capability stands for, so anything else can't be trusted to work
+    if download_context.encryption.is_some() && !auth.capabilities.iter().any(|capability| capability == Capability::Encryption.key()) {
+        return Err(anyhow!("server sent an encrypted download context without agreeing to the encryption capability, authenticate again to negotiate it"));
+    }
+    Ok(download_context)
+}
+
+// Set BUCKET_RETRY_SEED to make the jitter reproducible, for tests and chasing timing bugs. It's one
+// sequence shared by every thread, so with more than one the order they draw from it can still vary.
+fn jitter_rng() -> &'static Mutex<StdRng> {
+    JITTER_RNG.get_or_init(|| {
+        let seed = env::var("BUCKET_RETRY_SEED").ok().and_then(|seed| seed.parse::<u64>().inspect_err(|e| eprintln!("ignoring BUCKET_RETRY_SEED {seed}: {e}")).ok());
+        Mutex::new(seed.map(StdRng::seed_from_u64).unwrap_or_else(make_rng))
+    })
+}
+
+// Exponential, so a struggling server gets more breathing room with every failure. Jittered by up
+// to half again, so buckets that failed together don't all come back at once.
+fn retry_delay(retry: usize) -> Duration {
+    jittered_delay(retry, &mut jitter_rng().lock().unwrap())
+}
+
+fn jittered_delay(retry: usize, rng: &mut StdRng) -> Duration {
+    let delay = RETRY_BASE_DELAY * 2u32.pow(retry.saturating_sub(1).min(6) as u32);
+    delay.mul_f64(1.0 + rng.random::<f64>() * 0.5)
+}
+
+// Only servers that send manifest hashes can tell us the version was republished, otherwise there's nothing to compare
+fn check_manifest_hash(expected: Option<&str>, actual: Option<&str>) -> Result<(), BucketError> {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) if !expected.eq_ignore_ascii_case(actual) => Err(BucketError::ManifestChanged),
+        _ => Ok(()),
+    }
+}
+
+fn is_manifest_changed(e: &anyhow::Error) -> bool {
+    matches!(BucketError::find(e), Some(BucketError::ManifestChanged))
+}
+
+// Context creation happens before anything downloads, so a single flaky response shouldn't end the run.
+// A context for a different manifest to manifest_hash isn't flaky though, so that isn't retried.
+fn create_download_context_with_retries(game_id: &str, version: &str, manifest_hash: Option<&str>, auth: &AuthData, client: &reqwest::blocking::Client) -> Result<DownloadContext, anyhow::Error> {
+    let mut retries = 0;
+    loop {
+        match create_download_context(game_id, version, auth, client) {
+            Ok(context) => {
+                check_manifest_hash(manifest_hash, context.manifest_hash.as_deref())?;
+                return Ok(context);
+            }
+            Err(e) => {
+                retries += 1;
+                if retries > RETRY_COUNT {
+                    return Err(e.context(format!("failed to create download context for {version} after {RETRY_COUNT} retries")));
+                }
+                eprintln!("failed to create download context for {version} ({e}), retrying ({retries}/{RETRY_COUNT})");
+                thread::sleep(retry_delay(retries));
+            }
+        }
+    }
+}
+
+// One per version in the manifest, created up front since they decide how buckets are split. Reused
+// from the state file while they're fresh, and saved to it when they aren't. The locked version's
+// contexts have to be for the manifest it was fetched as.
+pub fn create_download_contexts(game_id: &str, manifest: &DropManifest, app_data: &AppData, state_file: &str, lock: Option<&ManifestLock>) -> Result<HashMap<String, DownloadContext>, anyhow::Error> {
+    let auth = app_data.auth.as_ref().expect("requires auth");
+    let versions = manifest.values().filter(|chunk| chunk.symlink.is_none()).map(|chunk| &chunk.version_name).collect::<HashSet<_>>();
+
+    let mut state = read_install_state(state_file)?;
+    state.forget_old_contexts(SAVED_CONTEXT_MAX_AGE);
+    let client = http::client();
+    let contexts = versions
+        .into_iter()
+        .map(|version| {
+            let manifest_hash = lock.filter(|lock| lock.version == *version).map(|lock| lock.hash.as_str());
+            // One saved for an earlier manifest is no use, but a fresh one will say whether it's still current
+            if let Some(context) = state.saved_context(game_id, version, SAVED_CONTEXT_MAX_AGE)
+                && check_manifest_hash(manifest_hash, context.manifest_hash.as_deref()).is_ok()
+            {
+                return Ok((version.clone(), context));
+            }
+            let context = create_download_context_with_retries(game_id, version, manifest_hash, auth, &client)?;
+            state.save_context(game_id, version, &context);
+            Ok((version.clone(), context))
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+    save_install_state(state_file, &state)?;
+
+    Ok(contexts)
 }
 
-pub fn download(game_id: String, buckets: Vec<DownloadBucket>, app_data: &AppData, args: &Args) {
+// Setting cancel stops the download at the next read, leaving whatever was written flushed to disk
+// stats is filled in however the download ends
+pub fn download(game_id: String, buckets: Vec<DownloadBucket>, download_contexts: &HashMap<String, DownloadContext>, app_data: &AppData, args: &Args, cancel: Arc<AtomicBool>, stats: &mut DownloadStats) -> Result<(), anyhow::Error> {
     let auth = app_data.auth.as_ref().expect("requires auth");
-    let threads = args.threads;
+    let threads = if args.serial { 1 } else { args.threads };
     let pool = ThreadPoolBuilder::new().num_threads(threads).build().expect("failed to create pool thread");
 
     println!("starting download with {} threads", threads);
 
-    let mut download_contexts = HashMap::<String, DownloadContext>::new();
-    let versions = buckets.iter().map(|e| &e.version).collect::<HashSet<_>>().into_iter().cloned().collect::<Vec<String>>();
-    
-    let client = reqwest::blocking::Client::new();
+    let client = http::client();
 
-    for version in versions {
-        let download_context = client
-            .post(auth.remote.join("/api/v2/client/context").expect("failed to generate download context url"))
-            .json(&ManifestBody {
-                game: game_id.clone(),
-                version: version.clone(),
-            })
-            .header("Authorization", generate_authorization_header(&auth))
-            .send()
-            .expect("failed to create download context");
+    // Only used with --buckets-per-context, keyed by version and the bucket's group within it
+    let group_contexts = &Mutex::new(HashMap::<(String, usize), Arc<DownloadContext>>::new());
+    // Fresh contexts for the versions whose saved one the server turned down
+    let replaced_contexts = &Mutex::new(HashMap::<String, Arc<DownloadContext>>::new());
+    let mut version_bucket_counts = HashMap::<String, usize>::new();
 
-        if download_context.status() != 200 {
-            panic!("failed to generate download context: {}", download_context.text().unwrap());
+    if args.probe_context {
+        for (version, context) in download_contexts {
+            // The smallest drop costs the least to throw away
+            let Some(drop) = buckets.iter().filter(|bucket| bucket.version == *version).flat_map(|bucket| &bucket.drops).min_by_key(|drop| drop.length) else {
+                continue;
+            };
+            match probe_context(drop, context, auth, &client) {
+                Ok(()) => {}
+                Err(e) if context.saved && is_context_rejected(&e) => {
+                    println!("the saved context for {version} was turned down, creating a fresh one");
+                    let fresh = create_download_context_with_retries(&game_id, version, context.manifest_hash.as_deref(), auth, &client)?;
+                    probe_context(drop, &fresh, auth, &client).map_err(|e| e.context(format!("the fresh download context for {version} doesn't work either")))?;
+                    replaced_contexts.lock().unwrap().insert(version.clone(), Arc::new(fresh));
+                }
+                Err(e) => return Err(e.context(format!("the download context for {version} doesn't work"))),
+            }
         }
+        println!("probed {} download contexts", download_contexts.len());
+    }
 
-        let download_context = download_context.json::<DownloadContext>().expect("failed to parse download context");
-        download_contexts.insert(version, download_context);
+    let failure = &Mutex::new(None::<anyhow::Error>);
+    // Paths the failed buckets were writing, for --on-failure clean
+    let touched = &Mutex::new(HashSet::<PathBuf>::new());
+    // Files from the buckets --keep-going carried on past, along with why
+    let skipped = &Mutex::new(Vec::<(String, String)>::new());
+
+    let total_size = buckets.iter().flat_map(|bucket| &bucket.drops).map(|drop| drop.length).sum::<usize>();
+    stats.total_bytes = total_size;
+    let range = auth.capabilities.iter().any(|capability| capability == Capability::Range.key());
+    if args.resume_large_files && !range {
+        println!("the server didn't agree to ranged chunk requests, so large files will download from the start (re-auth if it has since been upgraded)");
     }
+    let shared = &BucketDownload {
+        auth,
+        client: &client,
+        cancel: &cancel,
+        downloaded: AtomicUsize::new(0),
+        total_size,
+        completed: stats.complete_bytes,
+        progress: Progress::new(args.progress, total_size, stats.complete_bytes),
+        buffers: BufferSizes {
+            read: args.read_buffer,
+            write: args.write_buffer,
+        },
+        failures: AtomicUsize::new(0),
+        max_total_failures: args.max_total_failures,
+        verify: !args.no_verify,
+        resume_large_files: args.resume_large_files && range,
+        set_mtime: args.set_mtime,
+        min_speed: args.min_speed,
+        checksum_on_read: args.checksum_on_read,
+        limit_retries_per_error_type: args.limit_retries_per_error_type,
+        fail_fast: args.fail_fast,
+        wait_for_unlock: args.wait_for_unlock,
+        memory: args.mem_budget.map(MemoryBudget::new),
+        dedupe_within_bucket: args.dedupe_within_bucket,
+        min_speed_window: Duration::from_secs(args.min_speed_window),
+        buckets_len: buckets.len(),
+        speed: Mutex::new(SpeedMeter::new()),
+        checksum_mismatches: Mutex::new(Vec::new()),
+    };
+    let game_id = &game_id;
+    let pause_file = &args.pause_file.as_ref().map(PathBuf::from).unwrap_or_else(|| Path::new(&args.install_dir).join(".bucket-pause"));
+    let paused = &AtomicBool::new(false);
 
-    let download_contexts = &download_contexts;
+    // Ends along with the pool, so it never logs past the end of the download
+    let finished = &AtomicBool::new(false);
+    thread::scope(|threads| {
+        if let Some(interval) = args.stats_interval {
+            threads.spawn(move || shared.log_stats(Duration::from_secs(interval), finished));
+        }
 
-    let client_ref = &client;
+        // FIFO, so buckets start in the order they were given
+        pool.scope_fifo(|scope| {
+            for (index, bucket) in buckets.iter().enumerate() {
+                let version_context = download_contexts.get(&bucket.version).expect("failed to find download context for version - did we generate them all?");
+                let group = args.buckets_per_context.map(|buckets_per_context| {
+                    let count = version_bucket_counts.entry(bucket.version.clone()).or_insert(0);
+                    *count += 1;
+                    (*count - 1) / buckets_per_context.get()
+                });
+
+                scope.spawn_fifo(move |_| {
+                    // Once anything has failed for good the run is over, so don't start anything new
+                    if failure.lock().unwrap().is_some() {
+                        return;
+                    }
 
-    let buckets_len = buckets.len();
+                    // Buckets already going carry on, only starting new ones waits
+                    while pause_file.exists() && !shared.cancel.load(Ordering::Relaxed) {
+                        if !paused.swap(true, Ordering::Relaxed) {
+                            shared.progress.println(format!("paused, remove {} to carry on", pause_file.display()));
+                        }
+                        thread::sleep(PAUSE_POLL_INTERVAL);
+                    }
+                    if paused.swap(false, Ordering::Relaxed) {
+                        shared.progress.println("resuming".to_owned());
+                    }
 
-    pool.scope(|scope| {
-        for (index, bucket) in buckets.iter().enumerate() {
-            let download_context = download_contexts.get(&bucket.version).expect("failed to find download context for version - did we generate them all?");
+                    // Minted without the lock held, so one slow request doesn't hold up every other group. Two buckets racing for
+                    // the same group may both mint one, but only the first in is kept.
+                    let group_context = group.and_then(|group| {
+                        if let Some(context) = group_contexts.lock().unwrap().get(&(bucket.version.clone(), group)) {
+                            return Some(context.clone());
+                        }
+                        match create_download_context_with_retries(game_id, &bucket.version, version_context.manifest_hash.as_deref(), auth, shared.client) {
+                            Ok(context) => Some(group_contexts.lock().unwrap().entry((bucket.version.clone(), group)).or_insert(Arc::new(context)).clone()),
+                            Err(e) => {
+                                shared.progress.println(format!("failed to create a context for bucket {index}, falling back to the version's: {e}"));
+                                None
+                            }
+                        }
+                    });
+                    let replaced_context = replaced_contexts.lock().unwrap().get(&bucket.version).cloned();
+                    let download_context = group_context.as_deref().or(replaced_context.as_deref()).unwrap_or(version_context);
 
-            scope.spawn(move |_| {
-                let start = Instant::now();
-                match download_game_bucket(&bucket, download_context, auth, client_ref) {
-                    Ok(()) => {
-                        let time = start.elapsed().as_secs_f64();
-                        let size = bucket.drops.iter().map(|v| v.length).sum::<usize>() / (1000 * 1000);
-                        let speed = (size as f64) / time;
-                        println!("{index}/{} - {speed:.2}MB/s - {:.2}MB/s estimated", buckets_len, speed * threads as f64);
+                    // Something else may be filling the disk too, so the reserve is checked again before every bucket
+                    if let Some(reserve) = args.min_free_space
+                        && let Some(drop) = bucket.drops.first()
+                        && let Ok(available) = fs4::available_space(drop.path.parent().unwrap())
+                        && available < reserve
+                    {
+                        failure.lock().unwrap().get_or_insert(anyhow!("only {available} bytes are free, less than the {reserve} --min-free-space has to keep free"));
                         return;
                     }
-                    Err(e) => {
-                        panic!("failed to download: {e:?}");
+
+                    let mut result = shared.download_bucket(index, bucket, download_context);
+                    // Every bucket after the first to find out shares the replacement, rather than minting its own
+                    if let Err((e, _)) = &result
+                        && download_context.saved
+                        && is_context_rejected(e)
+                    {
+                        let mut replaced = replaced_contexts.lock().unwrap();
+                        let fresh = match replaced.get(&bucket.version) {
+                            Some(context) => Ok(context.clone()),
+                            None => {
+                                shared.progress.println(format!("the saved context for {} was turned down, creating a fresh one", bucket.version));
+                                create_download_context_with_retries(game_id, &bucket.version, version_context.manifest_hash.as_deref(), auth, shared.client).map(|context| replaced.entry(bucket.version.clone()).or_insert(Arc::new(context)).clone())
+                            }
+                        };
+                        drop(replaced);
+                        match fresh {
+                            Ok(context) => result = shared.download_bucket(index, bucket, &context),
+                            Err(e) => shared.progress.println(format!("failed to replace the saved context for {}: {e}", bucket.version)),
+                        }
+                    }
+
+                    if let Err((e, remaining)) = result {
+                        touched.lock().unwrap().extend(remaining.iter().map(|drop| drop.path.clone()));
+                        // A full drive, a broken server or a changed manifest fails everything that comes after too, so those still end the run
+                        if args.keep_going && !is_storage_full(&e) && !is_manifest_changed(&e) && !shared.gave_up() {
+                            shared.progress.println(format!("bucket {index} failed, carrying on without it: {e}"));
+                            skipped.lock().unwrap().extend(remaining.into_iter().map(|drop| (drop.filename, format!("{e:#}"))));
+                        } else {
+                            failure.lock().unwrap().get_or_insert(e);
+                        }
                     }
+                });
+            }
+        });
+        finished.store(true, Ordering::Relaxed);
+    });
+
+    shared.progress.finish();
+
+    let replaced_contexts = replaced_contexts.lock().unwrap();
+    if !replaced_contexts.is_empty() {
+        let mut state = read_install_state(&args.state_file)?;
+        for (version, context) in replaced_contexts.iter() {
+            state.save_context(game_id, version, context);
+        }
+        save_install_state(&args.state_file, &state)?;
+    }
+    stats.downloaded_bytes = shared.downloaded.load(Ordering::Relaxed);
+    stats.failed_attempts = shared.failures.load(Ordering::Relaxed);
+    let checksum_mismatches = std::mem::take(&mut *shared.checksum_mismatches.lock().unwrap());
+    stats.checksum_mismatches = checksum_mismatches.iter().map(|(filename, index)| format!("{filename} chunk {index}")).collect();
+    // Even the ones a retry fixed, so --resume-from-report checks them again rather than taking the install as good
+    let mut mismatched_files = checksum_mismatches.into_iter().map(|(filename, _)| filename).collect::<Vec<_>>();
+    mismatched_files.sort();
+    mismatched_files.dedup();
+    stats.failed_files = mismatched_files.clone();
+
+    let clean_up = || {
+        if args.on_failure == OnFailure::Clean {
+            for path in touched.lock().unwrap().iter() {
+                if let Err(e) = fs::remove_file(path)
+                    && e.kind() != io::ErrorKind::NotFound
+                {
+                    println!("failed to clean up {}: {e}", path.display());
                 }
-            });
+            }
         }
-    });
+    };
+
+    if let Some(e) = failure.lock().unwrap().take() {
+        clean_up();
+        return Err(e.context("failed to download"));
+    }
+
+    let mut skipped = skipped.lock().unwrap();
+    if !skipped.is_empty() {
+        clean_up();
+        skipped.sort();
+        skipped.dedup_by(|a, b| a.0 == b.0);
+        stats.failed_files.extend(skipped.iter().map(|(filename, _)| filename.clone()));
+        stats.failed_files.sort();
+        stats.failed_files.dedup();
+        println!("{} files couldn't be downloaded:", skipped.len());
+        for (filename, e) in skipped.iter() {
+            println!("{filename}: {e}");
+        }
+        print_mismatched_files(&mismatched_files);
+        return Err(anyhow!("failed to download {} files", skipped.len()));
+    }
 
+    if cancel.load(Ordering::Relaxed) {
+        return Err(BucketError::Cancelled.into());
+    }
+
+    if args.keep_going {
+        print_mismatched_files(&mismatched_files);
+    }
     println!("finished download!");
+
+    Ok(())
+}
+
+// Part of the --keep-going summary, since a file that came down corrupt once is worth a look even if a retry fixed it
+fn print_mismatched_files(files: &[String]) {
+    if files.is_empty() {
+        return;
+    }
+    println!("{} files failed their checksum at least once, and are listed for --resume-from-report:", files.len());
+    for filename in files {
+        println!("{filename}");
+    }
+}
+
+// Everything the bucket workers share for the length of a download
+struct BucketDownload<'a> {
+    auth: &'a AuthData,
+    client: &'a reqwest::blocking::Client,
+    cancel: &'a AtomicBool,
+    downloaded: AtomicUsize,
+    total_size: usize,
+    // Already on disk before the download started, which the percentages count as done
+    completed: usize,
+    progress: Progress,
+    buffers: BufferSizes,
+    buckets_len: usize,
+    speed: Mutex<SpeedMeter>,
+    // Failed attempts across every bucket, towards --max-total-failures
+    failures: AtomicUsize,
+    max_total_failures: Option<usize>,
+    // (path, chunk index)
+    checksum_mismatches: Mutex<Vec<(String, usize)>>,
+    verify: bool,
+    resume_large_files: bool,
+    set_mtime: Option<SetMtime>,
+    min_speed: Option<usize>,
+    min_speed_window: Duration,
+    checksum_on_read: bool,
+    limit_retries_per_error_type: bool,
+    fail_fast: bool,
+    wait_for_unlock: bool,
+    memory: Option<MemoryBudget>,
+    dedupe_within_bucket: bool,
+}
+
+// --mem-budget, lent to buckets for as long as their buffers are allocated
+struct MemoryBudget {
+    budget: usize,
+    available: Mutex<usize>,
+    returned: Condvar,
+}
+
+impl MemoryBudget {
+    fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            available: Mutex::new(budget),
+            returned: Condvar::new(),
+        }
+    }
+
+    // Blocks until enough has been given back. Capped at the whole budget, so a bucket that can't shrink any
+    // further still gets to run, once it has the budget to itself
+    fn acquire(&self, bytes: usize) -> MemoryLease<'_> {
+        let bytes = bytes.min(self.budget);
+        let mut available = self.returned.wait_while(self.available.lock().unwrap(), |available| *available < bytes).unwrap();
+        *available -= bytes;
+        MemoryLease { budget: self, bytes }
+    }
+}
+
+struct MemoryLease<'a> {
+    budget: &'a MemoryBudget,
+    bytes: usize,
+}
+
+impl Drop for MemoryLease<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.bytes;
+        self.budget.returned.notify_all();
+    }
+}
+
+impl BucketDownload<'_> {
+    // The --stats-interval heartbeat, for logs where a progress bar is no use
+    fn log_stats(&self, interval: Duration, finished: &AtomicBool) {
+        let mut since = Instant::now();
+        while !finished.load(Ordering::Relaxed) {
+            thread::sleep(PAUSE_POLL_INTERVAL);
+            if since.elapsed() < interval {
+                continue;
+            }
+            since = Instant::now();
+
+            let downloaded = self.downloaded.load(Ordering::Relaxed);
+            let speed = self.speed.lock().unwrap().sample(downloaded);
+            let progress = self.percent(downloaded);
+            let eta = match speed {
+                0.0 => "unknown".to_owned(),
+                speed => HumanDuration(Duration::from_secs_f64(self.total_size.saturating_sub(downloaded) as f64 / speed)).to_string(),
+            };
+            self.progress
+                .println(format!("{} of {} ({progress:.1}%), {}/s, eta {eta}", HumanBytes(downloaded as u64), HumanBytes(self.total_size as u64), HumanBytes(speed as u64)));
+        }
+    }
+
+    fn percent(&self, downloaded: usize) -> f64 {
+        (self.completed + downloaded) as f64 / (self.completed + self.total_size) as f64 * 100.0
+    }
+
+    // Whether --max-total-failures has been reached
+    fn gave_up(&self) -> bool {
+        self.max_total_failures.is_some_and(|max_total_failures| self.failures.load(Ordering::Relaxed) >= max_total_failures)
+    }
+
+    // Retries until the bucket is on disk, or returns the error along with the drops that never made it
+    fn download_bucket(&self, index: usize, bucket: &DownloadBucket, download_context: &DownloadContext) -> Result<(), (anyhow::Error, Vec<DownloadDrop>)> {
+        let mut remaining = bucket.clone();
+        let mut budget = RetryBudget::default();
+        let mut waiting_for_unlock = false;
+        loop {
+            if self.cancel.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+
+            let mut attempt_bytes = 0;
+            let on_progress = |bytes| {
+                attempt_bytes += bytes;
+                self.progress.update(self.downloaded.fetch_add(bytes, Ordering::Relaxed) + bytes);
+            };
+            match self.download_game_bucket(&remaining, download_context, on_progress) {
+                Ok(()) => {
+                    let downloaded = self.downloaded.load(Ordering::Relaxed);
+                    let speed = self.speed.lock().unwrap().sample(downloaded) / (1000.0 * 1000.0);
+                    let progress = self.percent(downloaded);
+                    self.progress.bucket_finished(format!("{index}/{} - {progress:.1}% - {speed:.2}MB/s", self.buckets_len));
+                    return Ok(());
+                }
+                Err(_) if self.cancel.load(Ordering::Relaxed) => return Ok(()),
+                // Retrying with the same context won't help, download swaps in a fresh one instead
+                Err(e) if download_context.saved && is_context_rejected(&e) => return Err((e, remaining.drops)),
+                // Retrying can't free up space, so there's no point spending the retries on it
+                Err(e) if is_storage_full(&e) => return Err((e.context(BucketError::DiskFull), remaining.drops)),
+                // Stopping everything else too, so nothing past the first bad chunk gets written
+                Err(e) if self.fail_fast && matches!(BucketError::find(&e), Some(BucketError::Checksum { .. })) => {
+                    self.cancel.store(true, Ordering::Relaxed);
+                    return Err((e, remaining.drops));
+                }
+                // Retrying on the usual schedule won't get the game closed, so either wait for as long as it takes or stop now
+                Err(e) if matches!(BucketError::find(&e), Some(BucketError::Locked(_))) => {
+                    if !self.wait_for_unlock {
+                        return Err((e, remaining.drops));
+                    }
+                    if let Some(BucketError::Locked(path)) = BucketError::find(&e)
+                        && !std::mem::replace(&mut waiting_for_unlock, true)
+                    {
+                        self.progress.println(format!("bucket {index} is waiting for {} to be closed, it's most likely in use by the game", path.display()));
+                    }
+                    self.progress.update(self.downloaded.fetch_sub(attempt_bytes, Ordering::Relaxed) - attempt_bytes);
+                    thread::sleep(UNLOCK_POLL_INTERVAL);
+                }
+                // Every retry would be mixing in content from the new manifest
+                Err(e) if is_manifest_changed(&e) => return Err((e, remaining.drops)),
+                Err(e) => {
+                    let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    if let Some(max_total_failures) = self.max_total_failures
+                        && failures >= max_total_failures
+                    {
+                        return Err((e.context(format!("giving up after {failures} failures across the download, the server or network looks broken")), remaining.drops));
+                    }
+
+                    let class = FailureClass::of(&e);
+                    match budget.next(index, &e, self.limit_retries_per_error_type) {
+                        Ok(delay) => thread::sleep(delay),
+                        Err(reason) => return Err((e.context(reason), remaining.drops)),
+                    }
+
+                    // Only the drops that didn't make it to disk intact need to be requested again
+                    let remaining_size = remaining.drops.iter().map(|drop| drop.length).sum::<usize>();
+                    if self.verify {
+                        remaining.drops.retain(|drop| !drop_is_valid(drop).unwrap_or(false));
+                    }
+                    let valid_size = remaining_size - remaining.drops.iter().map(|drop| drop.length).sum::<usize>();
+                    self.downloaded.fetch_sub(attempt_bytes, Ordering::Relaxed);
+                    self.progress.update(self.downloaded.fetch_add(valid_size, Ordering::Relaxed) + valid_size);
+                    self.progress
+                        .println(format!("bucket {index} failed ({class}: {e}), retrying {} of {} drops ({}/{RETRY_COUNT})", remaining.drops.len(), bucket.drops.len(), budget.retries));
+                    if remaining.drops.is_empty() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn download_game_bucket(&self, bucket: &DownloadBucket, context: &DownloadContext, mut on_progress: impl FnMut(usize)) -> Result<(), anyhow::Error> {
+        let mut drops = bucket.drops.clone();
+        // Only large files get a bucket to themselves, and they're the only ones worth resuming
+        let resumable = self.resume_large_files && matches!(drops.as_slice(), [drop] if drop.length >= TARGET_BUCKET_SIZE);
+        if resumable {
+            let drop = &mut drops[0];
+            drop.offset = resume_offset(drop);
+            if drop.offset != 0 {
+                self.progress.println(format!("resuming {} chunk {} from byte {} of {}", drop.filename, drop.index, drop.offset, drop.length));
+                on_progress(drop.offset);
+            }
+        }
+
+        // Only the first drop with each checksum is requested, the rest are copied from it once it's on disk
+        let mut duplicates = Vec::new();
+        if self.dedupe_within_bucket && !resumable {
+            let mut sources = HashMap::<(String, usize), DownloadDrop>::new();
+            drops.retain(|drop| match sources.get(&(drop.checksum.to_lowercase(), drop.length)) {
+                Some(source) => {
+                    duplicates.push((source.clone(), drop.clone()));
+                    false
+                }
+                None => {
+                    sources.insert((drop.checksum.to_lowercase(), drop.length), drop.clone());
+                    true
+                }
+            });
+        }
+
+        // Taken before the connection, so a bucket waiting on memory doesn't hold one up
+        let buffers = match &self.memory {
+            Some(memory) => self.buffers.within(memory.budget, drops.len()),
+            None => self.buffers,
+        };
+        let _memory = self.memory.as_ref().map(|memory| memory.acquire(buffers.cost(drops.len())));
+
+        // Held until the body has been copied, since that's what keeps the connection busy
+        let _permit = http::acquire_host(&self.auth.remote);
+        let (response, drops) = request_chunk(&drops, context, self.auth, self.client)?;
+
+        let mut pipeline = DropDownloadPipeline::new(response, drops.clone(), buffers, self.verify, resumable, self.set_mtime)?;
+        pipeline.watchdog = self.min_speed.map(|min_speed| Watchdog::new(min_speed, self.min_speed_window));
+        pipeline.checksum_on_read = self.checksum_on_read;
+        pipeline.encryption = context.encryption.clone();
+
+        let _completed = pipeline.copy(&mut on_progress, self.cancel)?;
+
+        if resumable {
+            drops.iter().for_each(remove_checkpoint);
+        }
+
+        if !self.verify {
+            pipeline.skip_checksum()?;
+            return self.copy_duplicates(&duplicates, on_progress);
+        }
+
+        let checksums = pipeline.finish()?;
+
+        // Every mismatch is counted, but the first is enough to fail the bucket. The retry only requests the drops that
+        // aren't valid on disk, and duplicates aren't copied from a bad source in the meantime.
+        let mut mismatch = None;
+        for (index, drop) in drops.iter().enumerate() {
+            if !checksum_matches(checksums.get(index).unwrap(), &drop.checksum)? {
+                self.checksum_mismatches.lock().unwrap().push((drop.filename.clone(), drop.index));
+                let e = anyhow::Error::new(BucketError::Checksum {
+                    path: format!("{} chunk {}", drop.filename, drop.index),
+                    expected: drop.checksum.clone(),
+                    actual: hex::encode(*checksums[index]),
+                });
+                // The checkpoint is gone, so the retry starts from scratch rather than trusting the same prefix again
+                if drop.offset != 0 {
+                    return Err(e.context(format!("resumed {} chunk {} didn't match its checksum", drop.filename, drop.index)));
+                }
+                mismatch.get_or_insert(e);
+            }
+        }
+        if let Some(mismatch) = mismatch {
+            return Err(mismatch);
+        }
+
+        self.copy_duplicates(&duplicates, on_progress)
+    }
+
+    fn copy_duplicates(&self, duplicates: &[(DownloadDrop, DownloadDrop)], mut on_progress: impl FnMut(usize)) -> Result<(), anyhow::Error> {
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+        copy_drops(duplicates, self.set_mtime)?;
+        on_progress(duplicates.iter().map(|(_, drop)| drop.length).sum());
+        Ok(())
+    }
 }
 
-fn download_game_bucket(bucket: &DownloadBucket, context: &DownloadContext, auth: &AuthData, client: &reqwest::blocking::Client) -> Result<(), anyhow::Error> {
-    let url = auth.remote.join("/api/v2/client/chunk").expect("failed to generate download url");
+fn is_storage_full(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|cause| matches!(cause.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded))
+}
 
-    let body = ChunkBody::create(context, &bucket.drops);
-    let response = client.post(url).json(&body).send()?;
+// How much retrying a failure is worth, with --limit-retries-per-error-type
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum FailureClass {
+    /// The server refused the request, and will again
+    Rejected,
+    /// The server broke, and might recover
+    ServerError,
+    /// Timeouts, dropped connections and rate limits, which usually pass
+    Transient,
+}
+
+impl FailureClass {
+    fn of(e: &anyhow::Error) -> Self {
+        match BucketError::find(e) {
+            Some(BucketError::Auth(_)) => FailureClass::Rejected,
+            Some(BucketError::ServerError { status, .. }) if status.is_client_error() && *status != StatusCode::TOO_MANY_REQUESTS => FailureClass::Rejected,
+            Some(BucketError::ServerError { status, .. }) if status.is_server_error() => FailureClass::ServerError,
+            _ => FailureClass::Transient,
+        }
+    }
+
+    fn retries(self) -> usize {
+        match self {
+            FailureClass::Rejected => 0,
+            // Kept apart from Transient for the logs, but a server that broke can just as well recover
+            FailureClass::ServerError | FailureClass::Transient => RETRY_COUNT,
+        }
+    }
+}
+
+impl Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureClass::Rejected => write!(f, "rejected"),
+            FailureClass::ServerError => write!(f, "server error"),
+            FailureClass::Transient => write!(f, "transient"),
+        }
+    }
+}
+
+// The retries a bucket has left
+#[derive(Default)]
+struct RetryBudget {
+    retries: usize,
+    // Resets are cheap to recover from, so they get their own, quicker, allowance, leaving the retries for everything else
+    resets: usize,
+    class_retries: HashMap<FailureClass, usize>,
+}
+
+impl RetryBudget {
+    // How long to wait before retrying after e, or why it's not worth retrying at all
+    fn next(&mut self, index: usize, e: &anyhow::Error, limit_per_class: bool) -> Result<Duration, String> {
+        if is_connection_reset(e) && self.resets < RESET_RETRY_COUNT {
+            self.resets += 1;
+            return Ok(RESET_RETRY_DELAY);
+        }
 
-    if response.status() != 200 {
-        return Err(anyhow!("failed to download chunk with response: {}", response.text().expect("failed to read response")));
+        self.retries += 1;
+        if self.retries > RETRY_COUNT {
+            return Err(format!("bucket {index} failed {} times", self.retries));
+        }
+        let class = FailureClass::of(e);
+        let class_retries = self.class_retries.entry(class).or_insert(0);
+        *class_retries += 1;
+        if limit_per_class && *class_retries > class.retries() {
+            return Err(format!("{class} errors get {} retries", class.retries()));
+        }
+        Ok(retry_delay(self.retries))
+    }
+}
+
+// The server turning down a chunk request outright, which for a saved context most likely means it expired
+fn is_context_rejected(e: &anyhow::Error) -> bool {
+    FailureClass::of(e) == FailureClass::Rejected
+}
+
+fn is_connection_reset(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|cause| matches!(cause.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe))
+}
+
+// Servers may send the files in a different order to the request, listing the request position of
+// each file in the body as Content-Order (e.g. "2,0,1"). Without the header, request order is assumed.
+fn response_order(response: &reqwest::blocking::Response, count: usize) -> Result<Vec<usize>, anyhow::Error> {
+    let Some(order) = response.headers().get("Content-Order") else {
+        return Ok((0..count).collect());
     };
+    let order = order.to_str()?;
+
+    let positions = order
+        .split(',')
+        .map(|position| position.trim().parse::<usize>())
+        .try_collect::<Vec<usize>>()
+        .map_err(|e| anyhow!("failed to parse Content-Order {order}: {e}"))?;
+    let mut seen = vec![false; count];
+    for position in &positions {
+        if *position >= count || std::mem::replace(&mut seen[*position], true) {
+            return Err(anyhow!("Content-Order {order} isn't an ordering of the {count} requested files"));
+        }
+    }
+    if positions.len() != count {
+        return Err(anyhow!("Content-Order {order} isn't an ordering of the {count} requested files"));
+    }
+
+    Ok(positions)
+}
+
+// Content-Lengths is the byte count of every file in the body, in the order they're sent, separated by commas
+// (e.g. "1,2,3"). Whitespace around the counts and a trailing comma are fine, and an empty header means no files.
+// There's no fallback to the body for it, since the body is nothing but the files' bytes back to back.
+fn parse_content_lengths(header: &str) -> Result<Vec<usize>, anyhow::Error> {
+    let lengths = header.trim();
+    let lengths = lengths.strip_suffix(',').unwrap_or(lengths);
+    if lengths.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+
+    lengths.split(',').map(|length| length.trim().parse::<usize>().map_err(|e| anyhow!("malformed Content-Lengths {header}: {e}"))).collect()
+}
+
+// Returns the response along with the drops in the order the server is sending them
+fn request_chunk(drops: &[DownloadDrop], context: &DownloadContext, auth: &AuthData, client: &reqwest::blocking::Client) -> Result<(reqwest::blocking::Response, Vec<DownloadDrop>), anyhow::Error> {
+    let url = auth.endpoint("/api/v2/client/chunk");
+
+    let body = ChunkBody::create(context, drops);
+    let mut response = http::send(client.post(url.clone()).json(&body).header("Authorization", generate_authorization_header(auth)))?;
+
+    // Downloads can run for hours, well past when the signature goes stale, so one fresh one is worth a try
+    // before this counts as a failure
+    if matches!(response.status(), StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+        response = http::send(client.post(url).json(&body).header("Authorization", generate_authorization_header(auth)))?;
+        let status = response.status();
+        if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+            return Err(BucketError::Auth(format!("{} turned down the chunk request with {status} even after signing it again, run bucket rotate-cert or authenticate again", auth.remote)).into());
+        }
+    }
+
+    let status = response.status();
+    if status != 200 {
+        return Err(anyhow::Error::new(BucketError::ServerError { status, body: response.text()? }).context("failed to download chunk"));
+    };
+
+    if let Some(manifest_hash) = response.headers().get("Manifest-Hash") {
+        check_manifest_hash(context.manifest_hash.as_deref(), Some(manifest_hash.to_str()?))?;
+    }
+
+    let ordered = response_order(&response, drops.len())?.into_iter().map(|position| drops[position].clone()).collect::<Vec<DownloadDrop>>();
+
+    let lengths = match response.headers().get("Content-Lengths") {
+        Some(lengths) => parse_content_lengths(lengths.to_str()?)?,
+        // A single file is the whole body, so the standard header says all there is to say
+        None if ordered.len() == 1 => vec![response.content_length().ok_or_else(|| anyhow!("server sent neither Content-Lengths nor Content-Length"))? as usize],
+        None => return Err(anyhow!("server didn't send Content-Lengths")),
+    };
+    if lengths.len() != ordered.len() {
+        return Err(anyhow!("invalid number of Content-Lengths received: {}, expected {}", lengths.len(), ordered.len()));
+    }
+    // Usually a glitch in how the server packed the response rather than anything lasting, so like any other
+    // unclassified error these go through the usual retries
+    for (drop, length) in ordered.iter().zip(&lengths) {
+        if drop.length - drop.offset != *length {
+            let from = if drop.offset == 0 { String::new() } else { format!(" from byte {}", drop.offset) };
+            return Err(anyhow!(
+                "{} chunk {} is {} bytes in the manifest, so {} bytes{from} were expected, but the server sent {length}",
+                drop.filename,
+                drop.index,
+                drop.length,
+                drop.length - drop.offset
+            ));
+        }
+    }
+
+    Ok((response, ordered))
+}
+
+// Asks for a single drop and hangs up as soon as the server has said yes, without reading any of it
+fn probe_context(drop: &DownloadDrop, context: &DownloadContext, auth: &AuthData, client: &reqwest::blocking::Client) -> Result<(), anyhow::Error> {
+    request_chunk(slice::from_ref(drop), context, auth, client).map(|_| ())
+}
+
+pub fn stream_file(game_id: &str, path: &str, manifest: &DropManifest, app_data: &AppData) -> Result<(), anyhow::Error> {
+    let auth = app_data.auth.as_ref().expect("requires auth");
+    let chunk = manifest.get(path).ok_or_else(|| anyhow!("{path} isn't in the manifest for {game_id}"))?;
+    if let Some(target) = &chunk.symlink {
+        return Err(anyhow!("{path} is a symlink to {target}"));
+    }
+
+    let drops = file_drops(path, Path::new(path).to_path_buf(), chunk);
+
+    let client = http::client();
+    let context = create_download_context_with_retries(game_id, &chunk.version_name, None, auth, &client)?;
+
+    // Chunks have to arrive in order, so they're fetched one request at a time
+    for drops in drops.chunks(context.max_files()) {
+        let (response, ordered) = request_chunk(drops, &context, auth, &client)?;
+        if ordered.iter().zip(drops).any(|(a, b)| a.index != b.index) {
+            return Err(anyhow!("server reordered the chunks of {path}, which can't be streamed"));
+        }
+
+        let mut pipeline = DropDownloadPipeline::to_stdout(response, drops.to_vec(), BufferSizes::default());
+        pipeline.encryption = context.encryption.clone();
+        pipeline.copy(|_| {}, &AtomicBool::new(false))?;
+        let checksums = pipeline.finish()?;
+
+        // The bytes are already on stdout by now, so all we can do is refuse to exit cleanly
+        for (drop, checksum) in drops.iter().zip(checksums.iter()) {
+            if !checksum_matches(checksum, &drop.checksum)? {
+                return Err(BucketError::Checksum {
+                    path: format!("{} chunk {}", drop.filename, drop.index),
+                    expected: drop.checksum.clone(),
+                    actual: hex::encode(**checksum),
+                }
+                .into());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Files already on disk only need the difference, since drops are written over them in place
+pub fn check_free_space(target_dir: &str, manifest: &DropManifest, reserve: u64) -> Result<(), anyhow::Error> {
+    let base_path = Path::new(target_dir);
+    let needed = manifest
+        .iter()
+        .filter(|(_, chunk)| chunk.symlink.is_none())
+        .map(|(raw_path, chunk)| {
+            let existing = fs::metadata(base_path.join(raw_path)).map(|metadata| metadata.len()).unwrap_or(0);
+            (chunk.lengths.iter().sum::<usize>() as u64).saturating_sub(existing)
+        })
+        .sum::<u64>();
+
+    let available = fs4::available_space(base_path)?;
+    if needed + reserve > available {
+        return Err(anyhow!("not enough space in {target_dir}: the download needs {needed} bytes and {reserve} have to stay free, but only {available} are available"));
+    }
+
+    Ok(())
+}
+
+// A cheap alternative to hashing everything: catches missing files and truncated writes
+pub fn check_sizes(install_dir: &str, manifest: &DropManifest) -> bool {
+    let base_path = Path::new(install_dir);
 
-    let lengths = response.headers().get("Content-Lengths").expect("server didn't send Content-Lengths").to_str().expect("failed to parse Content-Lengths header");
-    for (i, raw_length) in lengths.split(",").enumerate() {
-        let length = raw_length.parse::<usize>().unwrap_or(0);
-        let Some(drop) = bucket.drops.get(i) else {
-            return Err(anyhow!("invalid number of Content-Lengths recieved: {i}, {lengths}"));
+    let mut missing = 0;
+    let mut expected_size = 0;
+    let mut actual_size = 0;
+    for (raw_path, chunk) in manifest {
+        let path = base_path.join(Path::new(&raw_path));
+        let Ok(metadata) = fs::symlink_metadata(&path) else {
+            println!("missing {raw_path}");
+            missing += 1;
+            continue;
         };
-        if drop.length != length {
-            return Err(anyhow!("for {}, expected {}, got {} ({})", drop.filename, drop.length, raw_length, length));
+        if chunk.symlink.is_some() {
+            continue;
         }
+
+        expected_size += chunk.lengths.iter().sum::<usize>();
+        actual_size += metadata.len() as usize;
     }
 
-    let mut pipeline = DropDownloadPipeline::new(response, bucket.drops.clone())?;
+    if missing != 0 || expected_size != actual_size {
+        println!("install doesn't match manifest: {missing} of {} files missing, {actual_size} bytes on disk, expected {expected_size}", manifest.len());
+        println!("run `bucket verify` to see which files are wrong");
+        return false;
+    }
 
-    let _completed = pipeline.copy()?;
+    true
+}
+
+// Swaps a fully verified staging dir into place, so nothing ever sees a half-updated install
+pub fn commit_staging(staging_dir: &str, install_dir: &str) -> io::Result<()> {
+    let install_path = Path::new(install_dir).components().as_path();
+    let sibling = |suffix: &str| {
+        let mut path = install_path.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let old_path = sibling(".bucket-old");
 
-    let checksums = pipeline.finish()?;
+    // A rename can't cross filesystems, so the staging dir is copied next to the install first, keeping the swap itself a rename
+    let staged = if same_filesystem(Path::new(staging_dir), install_path.parent().unwrap_or(Path::new(".")))? {
+        PathBuf::from(staging_dir)
+    } else {
+        let new_path = sibling(".bucket-new");
+        println!("{staging_dir} is on a different filesystem to {install_dir}, copying it over...");
+        if new_path.exists() {
+            fs::remove_dir_all(&new_path)?;
+        }
+        copy_dir(Path::new(staging_dir), &new_path)?;
+        new_path
+    };
 
-    for (index, drop) in bucket.drops.iter().enumerate() {
-        let res = hex::encode(**checksums.get(index).unwrap());
-        if res != drop.checksum {
-            println!("context didn't match... doing nothing because we will validate later.");
-            // return Ok(false);
-            // return Err(ApplicationDownloadError::Checksum);
+    if old_path.exists() {
+        fs::remove_dir_all(&old_path)?;
+    }
+    if install_path.exists() {
+        fs::rename(install_path, &old_path)?;
+    }
+    if let Err(e) = fs::rename(&staged, install_path) {
+        // Put the old install back rather than leaving nothing
+        if old_path.exists() {
+            fs::rename(&old_path, install_path)?;
         }
+        return Err(e);
+    }
+    if old_path.exists() {
+        fs::remove_dir_all(&old_path)?;
+    }
+    if staged != Path::new(staging_dir) {
+        fs::remove_dir_all(staging_dir)?;
     }
 
     Ok(())
 }
+
+// Found out by trying, since a probe file moving between them is the only check that works everywhere
+pub fn same_filesystem(from: &Path, to: &Path) -> io::Result<bool> {
+    let probe = from.join(".bucket-rename-test");
+    let moved = to.join(".bucket-rename-test");
+    fs::write(&probe, [])?;
+    let result = fs::rename(&probe, &moved);
+    let _ = fs::remove_file(&probe);
+    let _ = fs::remove_file(&moved);
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Keeps modes and mtimes, since verify and --set-mtime care about both, and recreates symlinks rather than following them
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (source, dest) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&source, &dest)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(fs::read_link(&source)?, &dest)?;
+        } else {
+            fs::copy(&source, &dest)?;
+            filetime::set_file_mtime(&dest, FileTime::from_last_modification_time(&entry.metadata()?))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Where --output-archive downloads to before packing, next to the archive so it's on the same disk, or in --tmp-dir
+pub fn archive_dir(archive: &str, tmp_dir: Option<&str>) -> String {
+    match tmp_dir {
+        Some(tmp_dir) => Path::new(tmp_dir).join(format!("{}.bucket-tmp", Path::new(archive).file_name().unwrap_or_default().to_string_lossy())).to_string_lossy().into_owned(),
+        None => format!("{archive}.bucket-tmp"),
+    }
+}
+
+// Where --tmp-dir stages a plain install, named after the install dir so installs to different dirs don't share one
+pub fn tmp_staging_dir(tmp_dir: &str, install_dir: &str) -> String {
+    let name = Path::new(install_dir).components().as_path().file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| "install".to_owned());
+    Path::new(tmp_dir).join(format!("{name}.bucket-staging")).to_string_lossy().into_owned()
+}
+
+// Entries go in path order at their manifest paths, so the same version always packs the same way
+pub fn pack_archive(dir: &str, manifest: &DropManifest, archive: &str) -> io::Result<()> {
+    let mut builder = tar::Builder::new(BufWriter::new(File::create(archive)?));
+
+    let mut raw_paths = manifest.keys().collect::<Vec<&String>>();
+    raw_paths.sort();
+    for raw_path in raw_paths {
+        let chunk = &manifest[raw_path];
+        let mut header = tar::Header::new_gnu();
+
+        if let Some(target) = &chunk.symlink {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            builder.append_link(&mut header, raw_path, target)?;
+            continue;
+        }
+
+        let file = File::open(Path::new(dir).join(raw_path))?;
+        let metadata = file.metadata()?;
+        header.set_size(metadata.len());
+        header.set_mode(chunk.permissions & 0o7777);
+        // Carries over --set-mtime, if it was used
+        header.set_mtime(metadata.modified().ok().and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()).map(|mtime| mtime.as_secs()).unwrap_or(0));
+        builder.append_data(&mut header, raw_path, file)?;
+    }
+
+    builder.into_inner()?.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A fresh dir per test, as they run in parallel
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("bucket-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("game")).unwrap();
+        dir
+    }
+
+    fn test_drop(filename: &str, length: usize) -> DownloadDrop {
+        DownloadDrop {
+            index: 0,
+            filename: filename.to_owned(),
+            path: PathBuf::from(filename),
+            start: 0,
+            length,
+            checksum: String::new(),
+            permissions: 0o644,
+            offset: 0,
+            mtime: None,
+        }
+    }
+
+    // Hands out what it has, then fails the way a server dropping the connection does
+    struct ResetAfter(io::Cursor<Vec<u8>>);
+
+    impl io::Read for ResetAfter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::ErrorKind::ConnectionReset.into()),
+                size => Ok(size),
+            }
+        }
+    }
+
+    #[test]
+    fn reset_partway_through_stream() {
+        let drops = vec![test_drop("a", 8), test_drop("b", 8)];
+        let source = ResetAfter(io::Cursor::new(vec![1; 12]));
+        let mut pipeline = DropDownloadPipeline::with_destinations(source, drops, vec![Vec::new(), Vec::new()], BufferSizes::default());
+        let e = anyhow::Error::from(pipeline.copy(|_| {}, &AtomicBool::new(false)).unwrap_err());
+        assert!(is_connection_reset(&e));
+        assert!(is_connection_reset(&e.context("failed to download chunk")));
+        assert!(!is_connection_reset(&anyhow!("timed out")));
+    }
+
+    #[test]
+    fn body_shorter_than_content_lengths() {
+        let drops = vec![test_drop("a", 8), test_drop("b", 8)];
+        let mut pipeline = DropDownloadPipeline::with_destinations(io::Cursor::new(vec![1; 12]), drops, vec![Vec::new(), Vec::new()], BufferSizes::default());
+        let e = pipeline.copy(|_| {}, &AtomicBool::new(false)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(e.to_string(), "response ended early in b chunk 0: expected 8 bytes, received 4");
+        // Only the drop that arrived whole was hashed, so the short one can't pass its checksum
+        assert_eq!(pipeline.digests.len(), 1);
+
+        let e = anyhow::Error::from(e);
+        assert_eq!(FailureClass::of(&e), FailureClass::Transient);
+        assert!(RetryBudget::default().next(0, &e, true).is_ok());
+    }
+
+    #[test]
+    fn resets_have_their_own_retries() {
+        let reset = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        let mut budget = RetryBudget::default();
+        for _ in 0..RESET_RETRY_COUNT {
+            assert_eq!(budget.next(0, &reset, false), Ok(RESET_RETRY_DELAY));
+        }
+        assert_eq!(budget.retries, 0);
+
+        // Past their own allowance, resets count like anything else
+        assert!(budget.next(0, &reset, false).unwrap() >= RETRY_BASE_DELAY);
+        assert_eq!(budget.retries, 1);
+        for _ in 1..RETRY_COUNT {
+            budget.next(0, &reset, false).unwrap();
+        }
+        assert!(budget.next(0, &reset, false).is_err());
+    }
+
+    #[test]
+    fn retries_per_error_type() {
+        let server_error = |status| anyhow::Error::new(BucketError::ServerError { status, body: String::new() });
+
+        let mut budget = RetryBudget::default();
+        assert!(budget.next(0, &server_error(StatusCode::NOT_FOUND), true).is_err());
+
+        let mut budget = RetryBudget::default();
+        for _ in 0..RETRY_COUNT {
+            budget.next(0, &server_error(StatusCode::INTERNAL_SERVER_ERROR), true).unwrap();
+        }
+        assert!(budget.next(0, &server_error(StatusCode::INTERNAL_SERVER_ERROR), true).is_err());
+
+        let mut budget = RetryBudget::default();
+        assert!(budget.next(0, &server_error(StatusCode::TOO_MANY_REQUESTS), true).is_ok());
+    }
+
+    #[test]
+    fn seeded_jitter_repeats() {
+        let delays = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (1..=RETRY_COUNT).map(|retry| jittered_delay(retry, &mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+
+        for (retry, delay) in (1..=RETRY_COUNT).zip(delays(7)) {
+            let base = RETRY_BASE_DELAY * 2u32.pow(retry.saturating_sub(1).min(6) as u32);
+            assert!(delay >= base && delay <= base.mul_f64(1.5));
+        }
+    }
+
+    #[test]
+    fn other_failures_leave_the_reset_retries() {
+        let reset = anyhow::Error::from(io::Error::from(io::ErrorKind::ConnectionReset));
+        let other = anyhow!("timed out");
+        let mut budget = RetryBudget::default();
+        for _ in 0..RETRY_COUNT {
+            budget.next(0, &other, false).unwrap();
+        }
+        assert!(budget.next(0, &other, false).is_err());
+        assert_eq!(budget.next(0, &reset, false), Ok(RESET_RETRY_DELAY));
+    }
+
+    fn is_outside(result: Result<(), BucketError>) -> bool {
+        matches!(result, Err(BucketError::OutsideInstallDir { .. }))
+    }
+
+    fn file_chunk(length: usize) -> DropChunk {
+        DropChunk {
+            permissions: 0o644,
+            ids: vec![String::new()],
+            checksums: vec![String::new()],
+            lengths: vec![length],
+            version_name: "1".to_owned(),
+            symlink: None,
+            mtime: None,
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn truncate_only_inside_install_dir() {
+        let dir = scratch_dir("truncate");
+        let base = dir.join("game");
+        fs::write(base.join("a"), "in").unwrap();
+        fs::write(dir.join("outside"), "out").unwrap();
+        std::os::unix::fs::symlink("../outside", base.join("link")).unwrap();
+        std::os::unix::fs::symlink("..", base.join("up")).unwrap();
+
+        let manifest = DropManifest::from([("a".to_owned(), file_chunk(2)), ("link".to_owned(), file_chunk(3))]);
+        truncate_files(&base.to_string_lossy(), &manifest).unwrap();
+        assert_eq!(fs::metadata(base.join("a")).unwrap().len(), 0);
+        // Left alone, rather than emptying whatever it points to
+        assert_eq!(fs::read_to_string(dir.join("outside")).unwrap(), "out");
+
+        for raw_path in ["../outside", "up/outside"] {
+            let manifest = DropManifest::from([(raw_path.to_owned(), file_chunk(3))]);
+            assert!(matches!(truncate_files(&base.to_string_lossy(), &manifest), Err(BucketError::OutsideInstallDir { .. })));
+        }
+        assert_eq!(fs::read_to_string(dir.join("outside")).unwrap(), "out");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn content_lengths() {
+        assert_eq!(parse_content_lengths("1,2,3").unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_content_lengths("1, 2, 3,").unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_content_lengths(" 1 ,2 , 3 , ").unwrap(), vec![1, 2, 3]);
+        assert_eq!(parse_content_lengths("7").unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn content_lengths_empty() {
+        assert!(parse_content_lengths("").unwrap().is_empty());
+        assert!(parse_content_lengths(" ").unwrap().is_empty());
+        assert!(parse_content_lengths(",").unwrap().is_empty());
+    }
+
+    #[test]
+    fn content_lengths_malformed() {
+        assert!(parse_content_lengths("1,,2").is_err());
+        assert!(parse_content_lengths("1,two,3").is_err());
+        assert!(parse_content_lengths("-1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_inside_install_dir() {
+        let dir = scratch_dir("symlink-inside");
+        let base = dir.join("game");
+        fs::create_dir(base.join("bin")).unwrap();
+        create_symlink(&base, &base.join("bin/run"), "../data/run").unwrap();
+        assert_eq!(fs::read_link(base.join("bin/run")).unwrap(), Path::new("../data/run"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlink_escaping_install_dir() {
+        let dir = scratch_dir("symlink-escaping");
+        let base = dir.join("game");
+        assert!(is_outside(create_symlink(&base, &base.join("a"), "../x")));
+        // Climbing out and back in is refused too, since the install dir's name shouldn't matter
+        assert!(is_outside(create_symlink(&base, &base.join("a"), "../../game/x")));
+        assert!(is_outside(create_symlink(&base, &base.join("a"), "../game/x")));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlink_absolute_target() {
+        let dir = scratch_dir("symlink-absolute");
+        let base = dir.join("game");
+        assert!(is_outside(create_symlink(&base, &base.join("a"), "/etc/passwd")));
+        // Even into the install dir, which would break as soon as it's moved
+        assert!(is_outside(create_symlink(&base, &base.join("a"), &base.join("x").to_string_lossy())));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_chained_through_earlier_symlink() {
+        let dir = scratch_dir("symlink-chained");
+        let base = dir.join("game");
+        create_symlink(&base, &base.join("d"), ".").unwrap();
+        // Lexically d/../x is inside, but d is the install dir itself, so it's really one level above
+        assert!(is_outside(create_symlink(&base, &base.join("d/e"), "../x")));
+        create_symlink(&base, &base.join("d/e"), "x").unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn link_chunk(target: &str) -> DropChunk {
+        DropChunk {
+            symlink: Some(target.to_owned()),
+            ..file_chunk(0)
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_made_after_directories() {
+        let dir = scratch_dir("symlink-order");
+        let base = dir.join("game");
+        // b points into a directory that only exists once c/d's parent is made
+        let manifest = DropManifest::from([("b".to_owned(), link_chunk("c/d")), ("c/d".to_owned(), file_chunk(0)), ("a".to_owned(), link_chunk("b"))]);
+        generate_buckets("game".to_owned(), &base.to_string_lossy(), &manifest, &HashMap::new(), None).unwrap();
+        assert_eq!(fs::read_link(base.join("a")).unwrap(), Path::new("b"));
+        assert_eq!(fs::read_link(base.join("b")).unwrap(), Path::new("c/d"));
+        assert!(base.join("c").is_dir());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlink_over_other_entries() {
+        let dir = scratch_dir("symlink-parent");
+        let base = dir.join("game");
+        for inner in [file_chunk(0), link_chunk("x")] {
+            let manifest = DropManifest::from([("a".to_owned(), link_chunk("b")), ("a/c".to_owned(), inner)]);
+            let result = generate_buckets("game".to_owned(), &base.to_string_lossy(), &manifest, &HashMap::new(), None);
+            assert!(matches!(result, Err(BucketError::SymlinkParent { .. })));
+            // Checked before anything is written
+            assert!(!base.join("a").exists());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_replaces_directory() {
+        let dir = scratch_dir("symlink-directory");
+        let base = dir.join("game");
+        fs::create_dir_all(base.join("a/b")).unwrap();
+        create_symlink(&base, &base.join("a"), "c").unwrap();
+        assert!(fs::symlink_metadata(base.join("a")).unwrap().is_symlink());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
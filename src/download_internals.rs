@@ -1,110 +1,479 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    error::Error,
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Read, Seek, SeekFrom, Stdout, Write},
     path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
+use aes::{Aes128, Aes192, Aes256};
+use ctr::{
+    Ctr128BE,
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+};
+use filetime::FileTime;
 use md5::{Context, Digest};
 use reqwest::blocking::Response;
+use ring::digest::{SHA256, digest};
+use serde::{Deserialize, Serialize};
 
-use crate::models::DownloadDrop;
+use crate::{
+    error::BucketError,
+    models::{ChunkEncryption, DownloadDrop, SetMtime},
+};
 
-static MAX_PACKET_LENGTH: usize = 4096 * 4;
+pub const MAX_PACKET_LENGTH: usize = 4096 * 4;
+pub const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+const MIN_WRITE_BUFFER_SIZE: usize = 4096;
 static BUMP_SIZE: usize = 4096 * 16;
+// How much of a checkpointed drop is written between checkpoints
+const CHECKPOINT_INTERVAL: usize = 8 * 1024 * 1024;
+
+// Returned (wrapped in an io::Error from copy) when a watchdog gives up on a slow download
+#[derive(Debug)]
+pub struct Stalled {
+    speed: f64,
+}
+impl Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "download stalled at {:.0}B/s", self.speed)
+    }
+}
+impl Error for Stalled {}
+
+// Catches connections that crawl along, making just enough progress that reads never time out
+pub struct Watchdog {
+    min_speed: usize,
+    window: Duration,
+    window_start: Instant,
+    window_bytes: usize,
+}
+impl Watchdog {
+    pub fn new(min_speed: usize, window: Duration) -> Self {
+        Self {
+            min_speed,
+            window,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    // Takes the bytes read since the last check, and fails once a whole window has been too slow
+    fn check(&mut self, bytes: usize) -> io::Result<()> {
+        self.window_bytes += bytes;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < self.window {
+            return Ok(());
+        }
+
+        let speed = self.window_bytes as f64 / elapsed.as_secs_f64();
+        if speed < self.min_speed as f64 {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, Stalled { speed }));
+        }
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct BufferSizes {
+    /// Bytes read from the response at a time
+    pub read: usize,
+    /// Bytes buffered per file before hitting the disk
+    pub write: usize,
+}
+impl BufferSizes {
+    // Every file in a request gets its write buffer up front, on top of the one read buffer
+    pub fn cost(&self, files: usize) -> usize {
+        self.read + self.write * files
+    }
+
+    // Write buffers shrunk, down to the smallest that's still worth having, until a request for files fits in budget
+    pub fn within(self, budget: usize, files: usize) -> Self {
+        if self.cost(files) <= budget {
+            return self;
+        }
+        Self {
+            read: self.read,
+            write: (budget.saturating_sub(self.read) / files.max(1)).clamp(MIN_WRITE_BUFFER_SIZE, self.write),
+        }
+    }
+}
+impl Default for BufferSizes {
+    fn default() -> Self {
+        Self {
+            read: MAX_PACKET_LENGTH,
+            write: WRITE_BUFFER_SIZE,
+        }
+    }
+}
+
+// Sharing and lock violations on Windows, and writing to a running executable on unix
+fn is_file_locked(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::ExecutableFileBusy || (cfg!(windows) && matches!(e.raw_os_error(), Some(32 | 33)))
+}
 
 pub struct DropWriter<W: Write> {
-    hasher: Context,
+    // None when verification is skipped, so nothing is spent hashing
+    hasher: Option<Context>,
     destination: BufWriter<W>,
 }
 impl DropWriter<File> {
-    fn new(path: PathBuf) -> Result<Self, io::Error> {
-        let destination = OpenOptions::new().write(true).create(true).truncate(false).open(&path)?;
+    fn new(path: PathBuf, start: usize, offset: usize, capacity: usize, hash: bool) -> Result<Self, io::Error> {
+        let mut destination = OpenOptions::new()
+            .read(offset != 0)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| if is_file_locked(&e) { io::Error::new(e.kind(), BucketError::Locked(path.clone())) } else { e })?;
+        let mut hasher = hash.then(Context::new);
+        // The digest has to cover the whole drop, so whatever an earlier attempt left is hashed again first
+        if offset != 0
+            && let Some(hasher) = &mut hasher
+        {
+            destination.seek(SeekFrom::Start(start.try_into().unwrap()))?;
+            io::copy(&mut (&mut destination).take(offset as u64), hasher)?;
+        }
+        if start + offset != 0 {
+            destination.seek(SeekFrom::Start((start + offset).try_into().unwrap()))?;
+        }
         Ok(Self {
-            destination: BufWriter::with_capacity(1024 * 1024, destination),
-            hasher: Context::new(),
+            destination: BufWriter::with_capacity(capacity, destination),
+            hasher,
         })
     }
+}
+impl<W: Write> DropWriter<W> {
+    fn with_destination(destination: W, capacity: usize, hash: bool) -> Self {
+        Self {
+            destination: BufWriter::with_capacity(capacity, destination),
+            hasher: hash.then(Context::new),
+        }
+    }
 
-    fn finish(mut self) -> io::Result<Digest> {
+    // None when verification is skipped
+    fn finish(mut self) -> io::Result<Option<Digest>> {
         self.flush()?;
-        Ok(self.hasher.finalize())
+        Ok(self.hasher.map(|hasher| hasher.finalize()))
     }
 }
-// Write automatically pushes to file and hasher
-impl Write for DropWriter<File> {
+// Write automatically pushes to destination and hasher
+impl<W: Write> Write for DropWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.hasher.write_all(buf).map_err(|e| io::Error::other(format!("Unable to write to hasher: {e}")))?;
         let bytes_written = self.destination.write(buf)?;
+        // Only hash what was actually written, or a short write would be hashed twice
+        if let Some(hasher) = &mut self.hasher {
+            hasher.write_all(&buf[..bytes_written]).map_err(|e| io::Error::other(format!("Unable to write to hasher: {e}")))?;
+        }
 
         Ok(bytes_written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.hasher.flush()?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.flush()?;
+        }
         self.destination.flush()
     }
 }
 // Seek moves around destination output
-impl Seek for DropWriter<File> {
+impl<W: Write + Seek> Seek for DropWriter<W> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         self.destination.seek(pos)
     }
 }
 
+// Hashes the drop's range on disk, or returns None if the file doesn't reach that far
+pub fn drop_digest(drop: &DownloadDrop) -> io::Result<Option<Digest>> {
+    let mut file = match File::open(&drop.path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if file.metadata()?.len() < (drop.start + drop.length) as u64 {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(drop.start.try_into().unwrap()))?;
+
+    let mut hasher = Context::new();
+    io::copy(&mut file.take(drop.length as u64), &mut hasher)?;
+
+    Ok(Some(hasher.finalize()))
+}
+
+// Checks whether the drop's range on disk already hashes to the expected checksum
+pub fn drop_is_valid(drop: &DownloadDrop) -> io::Result<bool> {
+    match drop_digest(drop)? {
+        Some(digest) => checksum_matches(&digest, &drop.checksum),
+        None => Ok(false),
+    }
+}
+
+// Decodes the expected checksum rather than encoding the digest, so the manifest's hex casing doesn't matter
+pub fn checksum_matches(digest: &Digest, expected: &str) -> io::Result<bool> {
+    let expected = hex::decode(expected).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("malformed checksum {expected} in manifest: {e}")))?;
+    if expected.len() != digest.len() {
+        return Ok(false);
+    }
+
+    // Constant time, so the comparison doesn't leak how much of the checksum matched
+    Ok(digest.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0)
+}
+
+// Left next to a file while one of its drops downloads, recording how much of the drop is safely on disk
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Checkpoint {
+    checksum: String,
+    written: usize,
+}
+
+fn checkpoint_path(drop: &DownloadDrop) -> PathBuf {
+    let mut path = drop.path.clone().into_os_string();
+    path.push(format!(".{}.bucket-resume", drop.index));
+    PathBuf::from(path)
+}
+
+fn save_checkpoint(drop: &DownloadDrop, written: usize) -> io::Result<()> {
+    let checkpoint = Checkpoint { checksum: drop.checksum.clone(), written };
+    fs::write(checkpoint_path(drop), serde_json::to_string(&checkpoint)?)
+}
+
+// How far into the drop an earlier attempt got, or 0 if there's no checkpoint for this exact chunk
+pub fn resume_offset(drop: &DownloadDrop) -> usize {
+    let Some(checkpoint) = fs::read_to_string(checkpoint_path(drop)).ok().and_then(|contents| serde_json::from_str::<Checkpoint>(&contents).ok()) else {
+        return 0;
+    };
+    if checkpoint.checksum != drop.checksum || checkpoint.written >= drop.length {
+        return 0;
+    }
+
+    // The file may have been truncated or removed since the checkpoint was written
+    match fs::metadata(&drop.path) {
+        Ok(metadata) if metadata.len() >= (drop.start + checkpoint.written) as u64 => checkpoint.written,
+        _ => 0,
+    }
+}
+
+pub fn remove_checkpoint(drop: &DownloadDrop) {
+    let _ = fs::remove_file(checkpoint_path(drop));
+}
+
+// A chunk's counter block is the first 16 bytes of SHA-256(nonce || filename || 0 || chunk index as a big endian
+// u64), counting up big endian from there. That keeps every chunk's keystream distinct without the server having to
+// send anything per chunk, and lets a ranged request start mid-chunk by seeking.
+pub fn decryptor(encryption: &ChunkEncryption, drop: &DownloadDrop) -> io::Result<Box<dyn StreamCipher + Send>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, format!("bad encryption in download context: {e}"));
+    let key = hex::decode(&encryption.key).map_err(|e| invalid(e.to_string()))?;
+    let nonce = hex::decode(&encryption.nonce).map_err(|e| invalid(e.to_string()))?;
+
+    let mut seed = nonce;
+    seed.extend_from_slice(drop.filename.as_bytes());
+    seed.push(0);
+    seed.extend_from_slice(&(drop.index as u64).to_be_bytes());
+    let seed = digest(&SHA256, &seed);
+    let iv = &seed.as_ref()[..16];
+
+    match key.len() {
+        16 => Ok(seeked(Ctr128BE::<Aes128>::new_from_slices(&key, iv).map_err(|e| invalid(e.to_string()))?, drop.offset)),
+        24 => Ok(seeked(Ctr128BE::<Aes192>::new_from_slices(&key, iv).map_err(|e| invalid(e.to_string()))?, drop.offset)),
+        32 => Ok(seeked(Ctr128BE::<Aes256>::new_from_slices(&key, iv).map_err(|e| invalid(e.to_string()))?, drop.offset)),
+        length => Err(invalid(format!("a key of {length} bytes isn't AES-128, 192 or 256"))),
+    }
+}
+
+// Seeked before boxing, since the box loses StreamCipherSeek
+fn seeked(mut cipher: impl StreamCipher + StreamCipherSeek + Send + 'static, offset: usize) -> Box<dyn StreamCipher + Send> {
+    cipher.seek(offset as u64);
+    Box::new(cipher)
+}
+
 pub struct DropDownloadPipeline<R: Read, W: Write> {
     pub source: R,
     pub drops: Vec<DownloadDrop>,
+    // Taken by copy as each drop starts, and finished as soon as the drop is written
     pub destination: Vec<DropWriter<W>>,
+    // One per drop copy has finished, in drop order
+    pub digests: Vec<Digest>,
+    pub read_buffer: usize,
+    // Whether copy keeps a checkpoint of each drop's progress on disk
+    pub checkpoint: bool,
+    pub set_mtime: Option<SetMtime>,
+    pub watchdog: Option<Watchdog>,
+    // Whether copy checks each drop's checksum as soon as it's read, rather than leaving it to finish
+    pub checksum_on_read: bool,
+    // Decrypts each drop as it's read, so hashing and writing only ever see plaintext
+    pub encryption: Option<ChunkEncryption>,
 }
 
 impl DropDownloadPipeline<Response, File> {
-    pub fn new(source: Response, drops: Vec<DownloadDrop>) -> Result<Self, io::Error> {
+    // With hash unset, nothing is hashed and skip_checksum has to be used instead of finish
+    pub fn new(source: Response, drops: Vec<DownloadDrop>, buffers: BufferSizes, hash: bool, checkpoint: bool, set_mtime: Option<SetMtime>) -> Result<Self, io::Error> {
         Ok(Self {
             source,
-            destination: drops.iter().map(|drop| DropWriter::new(drop.path.clone())).try_collect()?,
+            destination: drops.iter().map(|drop| DropWriter::new(drop.path.clone(), drop.start, drop.offset, buffers.write, hash)).try_collect()?,
             drops,
+            digests: Vec::new(),
+            read_buffer: buffers.read,
+            checkpoint,
+            set_mtime,
+            watchdog: None,
+            checksum_on_read: false,
+            encryption: None,
         })
     }
+}
 
-    pub fn copy(&mut self) -> Result<bool, io::Error> {
-        let mut copy_buffer = [0u8; MAX_PACKET_LENGTH];
-        for (index, drop) in self.drops.iter().enumerate() {
-            let destination = self.destination.get_mut(index).ok_or(io::Error::other("no destination")).unwrap();
-            let mut remaining = drop.length;
-            if drop.start != 0 {
-                destination.seek(SeekFrom::Start(drop.start.try_into().unwrap()))?;
-            }
-            let mut last_bump = 0;
+impl DropDownloadPipeline<Response, Stdout> {
+    // Every drop is written, in order, to stdout rather than to its file
+    pub fn to_stdout(source: Response, drops: Vec<DownloadDrop>, buffers: BufferSizes) -> Self {
+        let destinations = drops.iter().map(|_| io::stdout()).collect();
+        Self::with_destinations(source, drops, destinations, buffers)
+    }
+}
+
+impl<R: Read, W: Write> DropDownloadPipeline<R, W> {
+    // Each drop is hashed and written to the destination at its position, from its first byte
+    pub fn with_destinations(source: R, drops: Vec<DownloadDrop>, destinations: Vec<W>, buffers: BufferSizes) -> Self {
+        Self {
+            source,
+            destination: destinations.into_iter().map(|destination| DropWriter::with_destination(destination, buffers.write, true)).collect(),
+            drops,
+            digests: Vec::new(),
+            read_buffer: buffers.read,
+            checkpoint: false,
+            set_mtime: None,
+            watchdog: None,
+            checksum_on_read: false,
+            encryption: None,
+        }
+    }
+
+    // on_progress is called with the bytes copied since its last call, at most once every BUMP_SIZE bytes
+    pub fn copy(&mut self, mut on_progress: impl FnMut(usize), cancel: &AtomicBool) -> Result<bool, io::Error> {
+        let mut copy_buffer = vec![0u8; self.read_buffer];
+        let mut last_bump = 0;
+        for (drop, mut destination) in self.drops.iter().zip(self.destination.drain(..)) {
+            let mut remaining = drop.length - drop.offset;
+            let mut since_checkpoint = 0;
+            let mut decryptor = self.encryption.as_ref().map(|encryption| decryptor(encryption, drop)).transpose()?;
             loop {
-                let size = MAX_PACKET_LENGTH.min(remaining);
+                if cancel.load(Ordering::Relaxed) {
+                    destination.flush()?;
+                    return Err(io::Error::other(BucketError::Cancelled));
+                }
+
+                let size = self.read_buffer.min(remaining);
                 let size = self.source.read(&mut copy_buffer[0..size]).inspect_err(|_| {
-                    println!("got error from {}", drop.filename);
+                    eprintln!("got error from {}", drop.filename);
                 })?;
+                // Otherwise a body shorter than its Content-Lengths would have this spin forever
+                if size == 0 && remaining != 0 {
+                    let expected = drop.length - drop.offset;
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("response ended early in {} chunk {}: expected {expected} bytes, received {}", drop.filename, drop.index, expected - remaining),
+                    ));
+                }
                 remaining -= size;
                 last_bump += size;
 
+                // Checked on every read, since a crawling connection may take ages to reach a bump
+                if let Some(watchdog) = &mut self.watchdog {
+                    watchdog.check(size)?;
+                }
+
+                if let Some(decryptor) = &mut decryptor {
+                    decryptor.apply_keystream(&mut copy_buffer[0..size]);
+                }
                 destination.write_all(&copy_buffer[0..size])?;
 
+                since_checkpoint += size;
+                if self.checkpoint && since_checkpoint >= CHECKPOINT_INTERVAL {
+                    // Only what's been flushed can be trusted to be there next time
+                    destination.flush()?;
+                    save_checkpoint(drop, drop.length - remaining)?;
+                    since_checkpoint = 0;
+                }
+
                 if last_bump > BUMP_SIZE {
-                    last_bump -= BUMP_SIZE;
+                    on_progress(last_bump);
+                    last_bump = 0;
                 }
 
                 if remaining == 0 {
                     break;
                 };
             }
+            // Finished now rather than with the rest of the bucket, which releases the writer and its buffer.
+            // Destinations may share an output (stdout) too, so this keeps buffered bytes from trailing into the next drop.
+            let Some(digest) = destination.finish()? else {
+                continue;
+            };
+
+            // Fails the bucket before any more of it is written, rather than after all of it is
+            if self.checksum_on_read && !checksum_matches(&digest, &drop.checksum)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    BucketError::Checksum {
+                        path: format!("{} chunk {}", drop.filename, drop.index),
+                        expected: drop.checksum.clone(),
+                        actual: hex::encode(*digest),
+                    },
+                ));
+            }
+            self.digests.push(digest);
+        }
+        if last_bump != 0 {
+            on_progress(last_bump);
         }
 
         Ok(true)
     }
 
-    #[allow(dead_code)]
-    fn debug_skip_checksum(self) {
-        self.destination.into_iter().for_each(|mut e| e.flush().unwrap());
+    // Every drop is already flushed by copy, this is only what comes after
+    pub fn skip_checksum(self) -> Result<(), io::Error> {
+        apply_mtimes(&self.drops, self.set_mtime)
     }
 
     pub fn finish(self) -> Result<Vec<Digest>, io::Error> {
-        let checksums = self.destination.into_iter().map(|e| e.finish()).try_collect()?;
-        Ok(checksums)
+        if self.digests.len() != self.drops.len() {
+            return Err(io::Error::other("drops weren't hashed"));
+        }
+        apply_mtimes(&self.drops, self.set_mtime)?;
+        Ok(self.digests)
+    }
+}
+
+// Fills in drops whose content is already on disk as another drop, as (source, destination) pairs
+pub fn copy_drops(pairs: &[(DownloadDrop, DownloadDrop)], set_mtime: Option<SetMtime>) -> io::Result<()> {
+    for (source, destination) in pairs {
+        let mut from = File::open(&source.path)?;
+        from.seek(SeekFrom::Start(source.start as u64))?;
+        let mut to = OpenOptions::new().write(true).create(true).truncate(false).open(&destination.path)?;
+        to.seek(SeekFrom::Start(destination.start as u64))?;
+        io::copy(&mut from.take(source.length as u64), &mut to)?;
+    }
+    apply_mtimes(&pairs.iter().map(|(_, destination)| destination.clone()).collect::<Vec<_>>(), set_mtime)
+}
+
+// Has to come after the last write, which would move the mtime again. Every drop of a file sets the
+// same time, so it doesn't matter which of them finishes last.
+fn apply_mtimes(drops: &[DownloadDrop], set_mtime: Option<SetMtime>) -> io::Result<()> {
+    for drop in drops {
+        let seconds = match set_mtime {
+            None => return Ok(()),
+            Some(SetMtime::Epoch(seconds)) => seconds,
+            Some(SetMtime::Manifest) => match drop.mtime {
+                Some(seconds) => seconds,
+                None => continue,
+            },
+        };
+        filetime::set_file_mtime(&drop.path, FileTime::from_unix_time(seconds, 0))?;
     }
+    Ok(())
 }
@@ -0,0 +1,129 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io,
+    path::PathBuf,
+};
+
+use reqwest::StatusCode;
+
+// The kinds of failure callers might want to handle differently, like retrying a network error but not a
+// checksum mismatch. Usually wrapped in an anyhow::Error with context, so find() digs them back out.
+#[derive(Debug)]
+pub enum BucketError {
+    /// The server didn't accept our credentials, or there aren't any
+    Auth(String),
+    Network(reqwest::Error),
+    Io(io::Error),
+    Checksum {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+    ManifestParse(serde_json::Error),
+    DiskFull,
+    Cancelled,
+    /// Something, usually the game itself, has the file open in a way that stops it being written
+    Locked(PathBuf),
+    /// A manifest path or symlink that would lead out of the install dir, with the symlink's target if it's one
+    OutsideInstallDir {
+        path: PathBuf,
+        target: Option<String>,
+    },
+    /// A manifest symlink that other manifest entries sit underneath, which would have them written wherever it points
+    SymlinkParent {
+        link: PathBuf,
+        path: PathBuf,
+    },
+    /// The server started serving a different manifest for the version partway through the download
+    ManifestChanged,
+    /// Anything but success from the server, along with what it said about it
+    ServerError {
+        status: StatusCode,
+        body: String,
+    },
+}
+
+impl Display for BucketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BucketError::Auth(reason) => write!(f, "authentication failed: {reason}"),
+            BucketError::Network(e) => write!(f, "network error: {e}"),
+            BucketError::Io(e) => write!(f, "{e}"),
+            BucketError::Checksum { path, expected, actual } => write!(f, "checksum mismatch for {path}: expected {expected}, got {actual}"),
+            BucketError::ManifestParse(e) => write!(f, "failed to parse manifest: {e}"),
+            BucketError::DiskFull => write!(f, "the install drive is full"),
+            BucketError::Cancelled => write!(f, "download cancelled"),
+            BucketError::Locked(path) => write!(f, "{} is in use, most likely by the game, close it and try again or pass --wait-for-unlock", path.display()),
+            BucketError::OutsideInstallDir { path, target: Some(target) } => write!(f, "refusing to create symlink {} -> {target}, it points outside of the install dir", path.display()),
+            BucketError::OutsideInstallDir { path, target: None } => write!(f, "refusing to write {}, it leads outside of the install dir", path.display()),
+            BucketError::SymlinkParent { link, path } => write!(f, "refusing to create symlink {}, {} is inside it in the manifest", link.display(), path.display()),
+            BucketError::ManifestChanged => write!(f, "the manifest changed on the server since the download started, please re-run"),
+            BucketError::ServerError { status, body } => write!(f, "server responded with {status}: {body}"),
+        }
+    }
+}
+
+impl Error for BucketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BucketError::Network(e) => Some(e),
+            BucketError::Io(e) => Some(e),
+            BucketError::ManifestParse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for BucketError {
+    fn from(e: reqwest::Error) -> Self {
+        BucketError::Network(e)
+    }
+}
+
+impl From<io::Error> for BucketError {
+    fn from(e: io::Error) -> Self {
+        BucketError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for BucketError {
+    fn from(e: serde_json::Error) -> Self {
+        BucketError::ManifestParse(e)
+    }
+}
+
+impl BucketError {
+    // Kept stable, since scripts branch on them. 1 is left for anything unclassified.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BucketError::Auth(_) => 2,
+            BucketError::Network(_) => 3,
+            BucketError::ServerError { .. } => 4,
+            BucketError::ManifestParse(_) => 5,
+            BucketError::Checksum { .. } => 6,
+            BucketError::DiskFull => 7,
+            BucketError::Io(_) => 8,
+            BucketError::ManifestChanged => 9,
+            BucketError::Locked(_) => 10,
+            BucketError::OutsideInstallDir { .. } => 11,
+            BucketError::SymlinkParent { .. } => 12,
+            BucketError::Cancelled => 130,
+        }
+    }
+
+    // Copy paths report failures as io::Errors, whose source() skips over the error they wrap, so those are unwrapped by hand
+    pub fn find(e: &anyhow::Error) -> Option<&BucketError> {
+        e.downcast_ref::<BucketError>().or_else(|| {
+            e.chain().find_map(|cause| {
+                cause
+                    .downcast_ref::<BucketError>()
+                    .or_else(|| cause.downcast_ref::<io::Error>().and_then(|cause| cause.get_ref()).and_then(|cause| cause.downcast_ref::<BucketError>()))
+            })
+        })
+    }
+}
+
+pub fn exit_code(e: &anyhow::Error) -> i32 {
+    BucketError::find(e).map(BucketError::exit_code).unwrap_or(1)
+}
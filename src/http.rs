@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{
+        Arc, Condvar, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use reqwest::{
+    Url,
+    blocking::{Client, RequestBuilder, Response},
+};
+use ring::digest::{SHA256, digest};
+use rustls::{
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+
+use crate::models::{Args, CertFingerprint, HttpVersion, IpVersion};
+
+static TRACE_REQUESTS: AtomicBool = AtomicBool::new(false);
+static MAX_MANIFEST_SIZE: AtomicU64 = AtomicU64::new(u64::MAX);
+// Shared so every request reuses the same connection pool and settings
+static CLIENT: OnceLock<Client> = OnceLock::new();
+static HOST_LIMITER: OnceLock<HostLimiter> = OnceLock::new();
+
+// Counting semaphore per host, for --max-connections-per-host
+struct HostLimiter {
+    limit: usize,
+    active: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+// Holds one of the host's connections until dropped
+pub struct HostPermit {
+    host: String,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let limiter = HOST_LIMITER.get().expect("permit outlived its limiter");
+        *limiter.active.lock().unwrap().get_mut(&self.host).unwrap() -= 1;
+        limiter.released.notify_all();
+    }
+}
+
+// Blocks until the host has a connection free. Only needed around requests whose body is read
+// after send returns (chunks), since that's when the connection is actually in use.
+pub fn acquire_host(url: &Url) -> Option<HostPermit> {
+    let limiter = HOST_LIMITER.get()?;
+    let host = url.host_str().unwrap_or_default().to_owned();
+
+    let mut active = limiter.active.lock().unwrap();
+    while *active.get(&host).unwrap_or(&0) >= limiter.limit {
+        active = limiter.released.wait(active).unwrap();
+    }
+    *active.entry(host.clone()).or_insert(0) += 1;
+
+    Some(HostPermit { host })
+}
+
+// Trusts exactly one certificate, by fingerprint, in place of the usual CA checks. That's what keeps
+// out a MITM with a certificate from a rogue but trusted CA, and lets self-signed servers be pinned too.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    fingerprint: CertFingerprint,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(&self, end_entity: &CertificateDer<'_>, _intermediates: &[CertificateDer<'_>], _server_name: &ServerName<'_>, _ocsp_response: &[u8], _now: UnixTime) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = digest(&SHA256, end_entity.as_ref());
+        if fingerprint.as_ref() != self.fingerprint.0 {
+            return Err(rustls::Error::General(format!("server certificate has fingerprint {}, which isn't the pinned one", hex::encode(fingerprint))));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    // The handshake still has to prove the server holds the pinned certificate's key
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn pinned_tls_config(fingerprint: CertFingerprint, http_version: HttpVersion) -> ClientConfig {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("failed to configure tls versions")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier { fingerprint, provider }))
+        .with_no_client_auth();
+    // reqwest only fills in ALPN for configs it builds itself, and HTTP/2 can't be negotiated without it
+    config.alpn_protocols = match http_version {
+        HttpVersion::Http1 => vec![b"http/1.1".to_vec()],
+        HttpVersion::Auto | HttpVersion::Http2 => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    };
+    config
+}
+
+const CONNECT_RETRY_COUNT: usize = 2;
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Response headers worth seeing when debugging the protocol
+const TRACED_HEADERS: [&str; 2] = ["Content-Lengths", "Retry-After"];
+
+pub fn configure(args: &Args) {
+    TRACE_REQUESTS.store(args.trace_requests, Ordering::Relaxed);
+    MAX_MANIFEST_SIZE.store(args.max_manifest_size, Ordering::Relaxed);
+
+    let mut builder = Client::builder();
+    // Binding to the unspecified address of a family means only that family can be connected to
+    builder = match args.ip_version {
+        IpVersion::Auto => builder,
+        IpVersion::V4 => builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        IpVersion::V6 => builder.local_address(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    };
+
+    // Sent alongside the Authorization nonce, for proxies in front of the server that want their own
+    if !args.headers.is_empty() {
+        builder = builder.default_headers(args.headers.iter().map(|header| (header.name.clone(), header.value.clone())).collect());
+    }
+
+    builder = match args.http_version {
+        HttpVersion::Auto => builder,
+        HttpVersion::Http1 => builder.http1_only(),
+        HttpVersion::Http2 => builder.http2_prior_knowledge(),
+    };
+
+    // Every connection goes through this client, so auth, manifest and chunk requests are all pinned
+    if let Some(fingerprint) = args.pin_cert {
+        builder = builder.use_preconfigured_tls(pinned_tls_config(fingerprint, args.http_version));
+    }
+
+    if let Some(limit) = args.max_connections_per_host {
+        builder = builder.pool_max_idle_per_host(limit.get());
+        let _ = HOST_LIMITER.set(HostLimiter {
+            limit: limit.get(),
+            active: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        });
+    }
+
+    if CLIENT.set(builder.build().expect("failed to build http client")).is_err() {
+        panic!("http client configured twice");
+    }
+}
+
+// Every endpoint is built here, so a server with its API mounted under a prefix by a reverse proxy only needs
+// --api-prefix. Paths start with a / and may carry a query.
+pub fn endpoint(server: &Url, api_prefix: &str, path: &str) -> Url {
+    let prefix = api_prefix.trim_matches('/');
+    let path = if prefix.is_empty() { path.to_owned() } else { format!("/{prefix}{path}") };
+    server.join(&path).unwrap_or_else(|e| panic!("failed to build {path} url on {server}: {e}"))
+}
+
+// Clients are reference counted, so this is cheap
+pub fn client() -> Client {
+    CLIENT.get().expect("http client used before it was configured").clone()
+}
+
+// Every request goes through here, so there's one place to hook wire activity
+pub fn send(request: RequestBuilder) -> reqwest::Result<Response> {
+    if !TRACE_REQUESTS.load(Ordering::Relaxed) {
+        return request.send();
+    }
+
+    let (client, request) = request.build_split();
+    let request = request?;
+    let authorization = if request.headers().contains_key("Authorization") { " (Authorization: <redacted>)" } else { "" };
+    eprintln!("> {} {}{authorization}", request.method(), request.url());
+
+    let response = client.execute(request).inspect_err(|e| eprintln!("< {e}"))?;
+    let headers = TRACED_HEADERS
+        .iter()
+        .filter_map(|name| response.headers().get(*name).map(|value| format!(" {name}: {}", value.to_str().unwrap_or("<invalid>"))))
+        .collect::<String>();
+    eprintln!("< {:?} {} {}{headers}", response.version(), response.status(), response.url());
+
+    Ok(response)
+}
+
+// Auth and manifest requests have no retry loop of their own, so without this a resolver or connect
+// hiccup right at startup would abort the whole command. Only connect failures (DNS included) are
+// retried, since the server never saw those requests.
+pub fn send_retrying_connect(request: RequestBuilder) -> reqwest::Result<Response> {
+    let mut attempts = 0;
+    loop {
+        // Streaming bodies can't be cloned, and get the one attempt
+        let Some(attempt) = request.try_clone() else {
+            return send(request);
+        };
+        match send(attempt) {
+            Err(e) if e.is_connect() && attempts < CONNECT_RETRY_COUNT => {
+                attempts += 1;
+                eprintln!("failed to connect, retrying in {}s: {e}", CONNECT_RETRY_DELAY.as_secs());
+                thread::sleep(CONNECT_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+// Reads a manifest or versions list, stopping at --max-manifest-size so a hostile or broken server
+// can't run the client out of memory. Content-Length is checked first, but can't be relied on.
+pub fn read_manifest_body(response: Response) -> io::Result<String> {
+    let limit = MAX_MANIFEST_SIZE.load(Ordering::Relaxed);
+    let too_large = || io::Error::new(io::ErrorKind::FileTooLarge, format!("server sent more than --max-manifest-size ({limit} bytes)"));
+    if response.content_length().is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+
+    let mut body = String::new();
+    response.take(limit.saturating_add(1)).read_to_string(&mut body)?;
+    if body.len() as u64 > limit {
+        return Err(too_large());
+    }
+    Ok(body)
+}
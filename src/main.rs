@@ -1,20 +1,36 @@
 #![feature(iterator_try_collect)]
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     env, fs,
-    io::{self, BufRead},
+    io::{self, BufRead, Write},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
 };
 
-use chrono::Utc;
+use anyhow::{Context, anyhow};
+use chrono::{DateTime, Utc};
 use clap::Parser;
 use droplet_rs::ssl::sign_nonce;
+use glob::Pattern;
+use indicatif::HumanBytes;
 use reqwest::Url;
+use ring::digest::{SHA256, digest};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    download::{download, generate_buckets},
-    models::{Args, DropManifest, GameVersion, HandshakeRequestBody, HandshakeResponse, InitiateRequestBody},
+    bench::bench,
+    download::{archive_dir, check_free_space, check_sizes, commit_staging, create_download_contexts, download, generate_buckets, order_buckets, pack_archive, plan_buckets, same_filesystem, stream_file, tmp_staging_dir, truncate_files},
+    download_internals::drop_is_valid,
+    error::BucketError,
+    models::{Args, Capability, Command, DownloadBucket, DropManifest, FileSort, GameSearchResult, GameVersion, HandshakeRequestBody, HandshakeResponse, InitiateRequestBody, ManifestFile, ManifestLock, RotateCertResponse, Target, UniqueManifest},
+    report::{DownloadPlan, DownloadReport, DownloadStats, read_report, save_plan, save_report},
+    state::{InstallRecord, read_install_state, save_install_state},
+    verify::{VerifyCache, VerifyFailure, install_digest, read_verify_cache, repair_permissions, save_verify_cache, verify},
 };
 
 #[derive(Serialize, Deserialize)]
@@ -23,31 +39,162 @@ struct AuthData {
     private: String,
     public: String,
     client_id: String,
+    /// Capabilities the server confirmed during the handshake
+    #[serde(default)]
+    capabilities: Vec<String>,
+    /// Put in front of every endpoint's path, when the server is behind a path-rewriting proxy
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    api_prefix: String,
+}
+
+impl AuthData {
+    fn endpoint(&self, path: &str) -> Url {
+        http::endpoint(&self.remote, &self.api_prefix, path)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct AppData {
+    /// Files from before this was added have none, and count as 0
+    #[serde(default)]
+    version: u64,
+    /// Kept in the credentials file instead, when there is one
+    #[serde(default)]
     auth: Option<AuthData>,
+    /// Where auth is kept apart from everything else, so bucket.json can be shared without the private key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    credentials: Option<String>,
 }
 
+mod bench;
 mod download;
-mod models;
 mod download_internals;
+mod error;
+mod http;
+mod models;
+mod progress;
+mod report;
+mod state;
+mod verify;
+
+const APP_DATA_PATH: &str = "./bucket.json";
+
+// Every server has it, whether or not it has channels of its own
+const LATEST_CHANNEL: &str = "latest";
+
+// Bumped whenever bucket.json changes in a way serde defaults can't absorb, with a step added to migrate_app_data
+const APP_DATA_VERSION: u64 = 1;
+
+// Upgrades an older layout to the current one, a version at a time
+fn migrate_app_data(value: &mut serde_json::Value) -> Result<(), anyhow::Error> {
+    let version = match value.get("version") {
+        Some(version) => version.as_u64().ok_or_else(|| anyhow!("version should be a number, got {version}"))?,
+        None => 0,
+    };
+    if version > APP_DATA_VERSION {
+        return Err(anyhow!("it's format {version}, from a newer bucket, and this one only reads up to {APP_DATA_VERSION}"));
+    }
+
+    for from in version..APP_DATA_VERSION {
+        match from {
+            // Unversioned files share the same layout, capabilities is already defaulted when missing
+            0 => {}
+            _ => unreachable!("no migration from bucket.json format {from}"),
+        }
+    }
+    value["version"] = APP_DATA_VERSION.into();
+
+    Ok(())
+}
+
+fn parse_app_data(contents: &str) -> Result<AppData, anyhow::Error> {
+    let mut value = serde_json::from_str::<serde_json::Value>(contents)?;
+    if !value.is_object() {
+        return Err(anyhow!("expected an object, got {value}"));
+    }
+    migrate_app_data(&mut value)?;
 
-const APP_DATA_PATH: &'static str = "./bucket.json";
+    // Goes through serde_path_to_error so the error says which field was wrong, not just what
+    let mut app_data = serde_path_to_error::deserialize::<_, AppData>(value).map_err(|e| anyhow!("{}: {}", e.path(), e.inner()))?;
 
-fn read_app_data() -> AppData {
+    // A credentials file that doesn't exist yet just means authenticating, the same as no auth in bucket.json
+    if let Some(credentials) = &app_data.credentials
+        && fs::exists(credentials)?
+    {
+        let contents = fs::read_to_string(credentials)?;
+        let value = serde_json::from_str::<serde_json::Value>(&contents).map_err(|e| anyhow!("credentials file {credentials}: {e}"))?;
+        app_data.auth = Some(serde_path_to_error::deserialize::<_, AuthData>(value).map_err(|e| anyhow!("credentials file {credentials}: {}: {}", e.path(), e.inner()))?);
+    }
+
+    Ok(app_data)
+}
+
+fn read_app_data(silent: bool) -> AppData {
     if fs::exists(APP_DATA_PATH).expect("failed to check for bucket.json") {
         let contents = fs::read_to_string(APP_DATA_PATH).expect("failed to read bucket.json");
-        let app_data = serde_json::from_str::<AppData>(&contents).expect("failed to parse bucket.json");
-        return app_data;
+        match parse_app_data(&contents) {
+            Ok(app_data) => return app_data,
+            Err(e) => {
+                eprintln!("{APP_DATA_PATH} is broken: {e}");
+                if silent {
+                    eprintln!("fix or remove it, or run without --silent to authenticate again");
+                    std::process::exit(1);
+                }
+
+                // The only thing in there is auth, so starting over just means authenticating again
+                if !confirm("discard it and authenticate again?") {
+                    std::process::exit(1);
+                }
+            }
+        }
     };
 
-    AppData { auth: None }
+    AppData {
+        version: APP_DATA_VERSION,
+        auth: None,
+        credentials: None,
+    }
 }
 
+// Written aside and renamed into place, so a crash never leaves half of a keypair behind
 fn save_app_data(app_data: &AppData) {
-    fs::write(APP_DATA_PATH, serde_json::to_string(app_data).expect("failed to serialize app_data")).expect("failed to save app data");
+    let mut value = serde_json::to_value(app_data).expect("failed to serialize app_data");
+    if let Some(credentials) = &app_data.credentials {
+        write_private(credentials, &serde_json::to_string(&app_data.auth).expect("failed to serialize credentials")).expect("failed to save credentials");
+        value["auth"] = serde_json::Value::Null;
+    }
+
+    let temp_path = format!("{APP_DATA_PATH}.tmp");
+    fs::write(&temp_path, value.to_string()).expect("failed to save app data");
+    fs::rename(&temp_path, APP_DATA_PATH).expect("failed to save app data");
+}
+
+// Only readable by the owner on unix, from the moment it's created, for files holding keys or tokens
+fn write_private(path: &str, contents: &str) -> io::Result<()> {
+    let temp_path = format!("{path}.tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    // An earlier temp file would keep its own mode, so it's started over
+    if let Err(e) = fs::remove_file(&temp_path)
+        && e.kind() != io::ErrorKind::NotFound
+    {
+        return Err(e);
+    }
+    options.open(&temp_path)?.write_all(contents.as_bytes())?;
+    fs::rename(&temp_path, path)
+}
+
+// Set for cat, whose stdout is the file itself, so prompts and notes have to go to stderr instead
+static STDOUT_IS_DATA: AtomicBool = AtomicBool::new(false);
+
+// Where prompts and anything else for the person at the terminal go
+fn console() -> Box<dyn Write> {
+    if STDOUT_IS_DATA.load(Ordering::Relaxed) { Box::new(io::stderr().lock()) } else { Box::new(io::stdout().lock()) }
 }
 
 fn shitty_write<T>(lock: &mut T, value: String)
@@ -58,58 +205,148 @@ where
     lock.flush().unwrap();
 }
 
-fn do_auth(app_data: &mut AppData) {
-    let mut lines = io::stdin().lock().lines();
-    let mut stdout_lock = io::stdout().lock();
-    shitty_write(&mut stdout_lock, format!("drop server url: "));
-    let server_url = Url::parse(&lines.next().unwrap().unwrap()).expect("failed to parse url");
+// Pasted input tends to come with stray spaces or a CR from Windows terminals, none of which is ever meant
+fn read_input(lines: &mut impl Iterator<Item = io::Result<String>>) -> String {
+    lines.next().unwrap().unwrap().trim().to_owned()
+}
 
-    let endpoint = server_url.join("/api/v1/client/auth/initiate").expect("failed to create initiate endpoint");
-    let body = InitiateRequestBody {
-        name: format!("bucket-cli"),
-        platform: env::consts::OS.to_string(),
-        capabilities: HashMap::new(),
-    };
+// Anything but a yes is a no
+fn confirm(question: &str) -> bool {
+    shitty_write(&mut console(), format!("{question} [y/N]: "));
+    let answer = read_input(&mut io::stdin().lock().lines());
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
 
-    let client = reqwest::blocking::Client::new();
-    let response = client.post(endpoint).json(&body).send().expect("failed to initiate auth");
+// The handshake response is "<client id>/<token>", though a paste can pick up doubled or surrounding slashes
+fn parse_handshake(handshake: &str) -> Result<(String, String), String> {
+    match handshake.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>()[..] {
+        [client_id, token] => Ok((client_id.to_owned(), token.to_owned())),
+        _ => Err(format!("expected the handshake response as <client id>/<token>, got \"{handshake}\"")),
+    }
+}
 
-    let mut callback = response.text().expect("failed to read callback url");
-    shitty_write(&mut stdout_lock, format!("open {}{} in your browser...\n", server_url, callback.split_off(1)));
+// Only prompts for the server if it isn't already known, from a link
+fn do_auth(app_data: &mut AppData, server_url: Option<Url>, args: &Args) -> Result<(), BucketError> {
+    let mut lines = io::stdin().lock().lines();
+    let mut console = console();
+    let server_url = server_url.unwrap_or_else(|| {
+        loop {
+            shitty_write(&mut console, "drop server url: ".to_owned());
+            let url = read_input(&mut lines);
+            match Url::parse(&url) {
+                Ok(url) => break url,
+                Err(e) => shitty_write(&mut console, format!("\"{url}\" isn't a valid url: {e}\n")),
+            }
+        }
+    });
 
-    shitty_write(&mut stdout_lock, "handshake response: ".to_owned());
-    let handshake = lines.next().unwrap().unwrap();
+    let api_prefix = args.api_prefix.clone().unwrap_or_default();
+    let endpoint = http::endpoint(&server_url, &api_prefix, "/api/v1/client/auth/initiate");
+    let body = InitiateRequestBody {
+        name: "bucket-cli".to_owned(),
+        platform: args.platform.clone().unwrap_or_else(|| env::consts::OS.to_string()),
+        capabilities: if args.capabilities.is_empty() {
+            Capability::supported().iter().map(|capability| (capability.key().to_owned(), ())).collect()
+        } else {
+            args.capabilities.iter().map(|capability| (capability.clone(), ())).collect()
+        },
+    };
 
-    let handshake = handshake.split('/').collect::<Vec<&str>>();
-    if handshake.len() != 2 {
-        panic!("handshake is expected to be in format .../...");
-    }
+    let client = http::client();
+    let response = http::send_retrying_connect(client.post(endpoint).json(&body))?;
 
-    let client_id = handshake.get(0).expect("failed to fetch client id from handshake");
-    let token = handshake.get(1).expect("failed to fetch token from handshake");
+    let mut callback = response.text()?;
+    shitty_write(&mut console, format!("open {}{} in your browser...\n", server_url, callback.split_off(1)));
 
-    let body = HandshakeRequestBody {
-        client_id: (*client_id).to_string(),
-        token: (*token).to_string(),
+    let (client_id, token) = loop {
+        shitty_write(&mut console, "handshake response: ".to_owned());
+        match parse_handshake(&read_input(&mut lines)) {
+            Ok(handshake) => break handshake,
+            Err(e) => shitty_write(&mut console, format!("{e}\n")),
+        }
     };
-    let endpoint = server_url.join("/api/v1/client/auth/handshake").expect("failed to make handshake url");
-    let response = client.post(endpoint).json(&body).send().expect("failed to complete handshake");
+
+    let body = HandshakeRequestBody { client_id, token };
+    let endpoint = http::endpoint(&server_url, &api_prefix, "/api/v1/client/auth/handshake");
+    let response = http::send_retrying_connect(client.post(endpoint).json(&body))?;
 
     if response.status() != 200 {
-        panic!("handshake failed with: {}", response.text().expect("failed to read handshake response"));
+        return Err(BucketError::Auth(format!("handshake failed with: {}", response.text()?)));
     }
 
-    let response = response.json::<HandshakeResponse>().expect("failed to parse handshake response");
+    let response = serde_json::from_str::<HandshakeResponse>(&response.text()?).map_err(|e| BucketError::Auth(format!("failed to parse handshake response: {e}")))?;
+    if !response.capabilities.is_empty() {
+        shitty_write(&mut console, format!("server confirmed capabilities: {}\n", response.capabilities.join(", ")));
+    }
 
     app_data.auth = Some(AuthData {
         remote: server_url,
         private: response.private,
         public: response.certificate,
         client_id: response.id,
+        capabilities: response.capabilities,
+        api_prefix,
     });
+    Ok(())
+}
+
+// None when the server has no search endpoint, so the input can only be taken as an exact game ID
+fn search_games(query: &str, auth: &AuthData) -> Result<Option<Vec<GameSearchResult>>, anyhow::Error> {
+    let mut endpoint = auth.endpoint("/api/v1/client/game/search");
+    endpoint.query_pairs_mut().append_pair("q", query);
+    let response = http::send(http::client().get(endpoint).header("Authorization", generate_authorization_header(auth)))?;
+
+    if response.status() == 404 {
+        return Ok(None);
+    }
+    if response.status() != 200 {
+        return Err(anyhow!("search failed with {}: {}", response.status(), response.text()?));
+    }
+    Ok(Some(response.json::<Vec<GameSearchResult>>()?))
 }
 
-fn fetch_params(args: &mut Args) -> (String, String) {
+// Lets the input be a search term as well as an ID, falling back to taking it as an ID whenever search can't help
+fn pick_game(input: String, searchable: &mut bool, auth: &AuthData, lines: &mut impl Iterator<Item = io::Result<String>>, console: &mut impl Write) -> Option<String> {
+    if !*searchable {
+        return Some(input);
+    }
+
+    let results = match search_games(&input, auth) {
+        Ok(Some(results)) => results,
+        Ok(None) => {
+            *searchable = false;
+            return Some(input);
+        }
+        Err(e) => {
+            shitty_write(console, format!("{e}, using \"{input}\" as a game ID\n"));
+            return Some(input);
+        }
+    };
+    if results.is_empty() {
+        shitty_write(console, format!("no games match \"{input}\", using it as a game ID\n"));
+        return Some(input);
+    }
+    if results.iter().any(|game| game.id == input) {
+        return Some(input);
+    }
+
+    for (index, game) in results.iter().enumerate() {
+        shitty_write(console, format!("{:>3}) {} ({})\n", index + 1, game.name, game.id));
+    }
+    loop {
+        shitty_write(console, format!("pick a game [1-{}], or nothing to search again: ", results.len()));
+        let answer = read_input(lines);
+        if answer.is_empty() {
+            return None;
+        }
+        match answer.parse::<usize>() {
+            Ok(number) if (1..=results.len()).contains(&number) => return Some(results[number - 1].id.clone()),
+            _ => shitty_write(console, format!("{answer} isn't one of the games listed\n")),
+        }
+    }
+}
+
+fn fetch_params(args: &mut Args, auth: &AuthData) -> (String, String) {
     if let Some(game) = &args.game
         && args.silent
     {
@@ -117,16 +354,21 @@ fn fetch_params(args: &mut Args) -> (String, String) {
     };
 
     if args.silent {
-        panic!("silent mode set, but game not specified")
+        exit_with(anyhow!("silent mode set, but game not specified"))
     };
 
     let mut lines = io::stdin().lock().lines();
-    let mut stdout_lock = io::stdout().lock();
+    let mut console = console();
 
+    let mut searchable = true;
     loop {
-        shitty_write(&mut stdout_lock, format!("game ID [{}]: ", args.game.clone().unwrap_or("<unset>".to_string())));
-        let game_id = lines.next().unwrap().unwrap();
-        if game_id.len() != 0 {
+        shitty_write(&mut console, format!("game ID or search [{}]: ", args.game.clone().unwrap_or("<unset>".to_string())));
+        let game_id = read_input(&mut lines);
+        if !game_id.is_empty() {
+            // Backing out of the picker means searching again, not falling back to the previous game
+            let Some(game_id) = pick_game(game_id, &mut searchable, auth, &mut lines, &mut console) else {
+                continue;
+            };
             args.game = Some(game_id)
         }
 
@@ -135,13 +377,29 @@ fn fetch_params(args: &mut Args) -> (String, String) {
         }
     }
 
-    shitty_write(&mut stdout_lock, format!("game version [{}]: ", args.game_version.clone().unwrap_or("<latest>".to_string())));
-    let game_version = lines.next().unwrap().unwrap();
-    if game_version.len() != 0 {
+    let default_version = args.game_version.clone().unwrap_or_else(|| format!("<{}>", args.channel.as_deref().unwrap_or(LATEST_CHANNEL)));
+    shitty_write(&mut console, format!("game version [{default_version}]: "));
+    let game_version = read_input(&mut lines);
+    if !game_version.is_empty() {
         args.game_version = Some(game_version);
     };
 
-    return (args.game.clone().unwrap().to_string(), args.game_version.clone().unwrap_or("".to_owned()));
+    (args.game.clone().unwrap().to_string(), args.game_version.clone().unwrap_or("".to_owned()))
+}
+
+// Checked up front so a bad install dir doesn't cost a manifest download first
+fn validate_install_dir(install_dir: &str) -> Result<(), anyhow::Error> {
+    let path = Path::new(install_dir);
+    if path.exists() && !path.is_dir() {
+        return Err(anyhow!("it exists and isn't a directory"));
+    }
+    fs::create_dir_all(path)?;
+
+    let probe = path.join(".bucket-write-test");
+    fs::write(&probe, []).map_err(|e| anyhow!("it isn't writable: {e}"))?;
+    fs::remove_file(&probe)?;
+
+    Ok(())
 }
 
 fn generate_authorization_header(certs: &AuthData) -> String {
@@ -152,65 +410,866 @@ fn generate_authorization_header(certs: &AuthData) -> String {
     format!("Nonce {} {} {}", certs.client_id, nonce, signature)
 }
 
-fn discover_latest_version(game_id: &str, auth: &AuthData) -> String {
-    let endpoint = auth.remote.join(&format!("/api/v1/client/game/versions?id={}", game_id)).expect("failed to build discovery url");
-    let client = reqwest::blocking::Client::new();
-    let response = client.get(endpoint).header("Authorization", generate_authorization_header(auth)).send().expect("failed to discover versions");
+fn discover_version(game_id: &str, channel: &str, auth: &AuthData) -> Result<String, anyhow::Error> {
+    let endpoint = auth.endpoint(&format!("/api/v1/client/game/versions?id={}", game_id));
+    let client = http::client();
+    let response = http::send_retrying_connect(client.get(endpoint).header("Authorization", generate_authorization_header(auth)))
+        .map_err(BucketError::Network)
+        .with_context(|| format!("failed to discover versions for game {game_id}"))?;
 
-    let versions = response.json::<Vec<GameVersion>>().expect("failed to parse versions");
+    let status = response.status();
+    if status != 200 {
+        let body = response.text().map_err(BucketError::Network).with_context(|| format!("failed to read versions error for game {game_id}"))?;
+        return Err(anyhow::Error::new(BucketError::ServerError { status, body }).context(format!("failed to discover versions for game {game_id}")));
+    }
 
-    let version = versions.get(0).expect("no versions available for game").version_name.clone();
+    let versions = http::read_manifest_body(response).map_err(|e| anyhow!("failed to read versions for game {game_id}: {e}"))?;
+    let versions = serde_json::from_str::<Vec<GameVersion>>(&versions).map_err(|e| anyhow!("failed to parse versions for game {game_id}: {e}"))?;
+    let version = pick_version(game_id, channel, &versions)?;
 
-    println!("found \"{}\" as latest version", version);
+    eprintln!("found \"{}\" as {channel} version", version);
 
-    return version;
+    Ok(version)
 }
 
-fn fetch_manifest(params: (String, String), app_data: &AppData) -> DropManifest {
-    println!("downloading game manifest...");
+// Versions come newest first, so the first one on the channel is its current one
+fn pick_version(game_id: &str, channel: &str, versions: &[GameVersion]) -> Result<String, anyhow::Error> {
+    if versions.is_empty() {
+        return Err(anyhow!("no versions available for game {game_id}"));
+    }
+    Ok(versions
+        .iter()
+        .find(|version| channel == LATEST_CHANNEL || version.channels.iter().any(|name| name.eq_ignore_ascii_case(channel)))
+        .ok_or_else(|| anyhow!("no version of game {game_id} is on the {channel} channel"))?
+        .version_name
+        .clone())
+}
 
+// Fills in the version from --channel, or the latest, if none was given, re-prompting for a game if it has none
+fn resolve_params(args: &mut Args, app_data: &AppData) -> (String, String) {
     let auth = app_data.auth.as_ref().expect("required auth data");
 
-    let version = { if params.1.len() == 0 { discover_latest_version(&params.0, auth) } else { params.1 } };
+    loop {
+        let params = fetch_params(args, auth);
+        if !params.1.is_empty() {
+            return params;
+        }
 
-    let url = auth.remote.join(&format!("/api/v1/client/game/manifest?id={}&version={}", params.0, version)).expect("failed to create manifest URL");
-    let client = reqwest::blocking::Client::new();
-    let response = client.get(url).header("Authorization", generate_authorization_header(auth)).send().expect("failed to fetch manifest");
+        match discover_version(&params.0, args.channel.as_deref().unwrap_or(LATEST_CHANNEL), auth) {
+            Ok(version) => return (params.0, version),
+            Err(e) => {
+                if args.silent {
+                    eprintln!("{e}");
+                    std::process::exit(1);
+                }
+                shitty_write(&mut console(), format!("{e}\n"));
+                args.game = None;
+            }
+        }
+    }
+}
 
-    if response.status() != 200 {
-        panic!("failed to fetch manifest: {}", response.text().expect("failed to read manifest error"));
+// The manifest exactly as the server sent it
+fn fetch_manifest_text(params: (String, String), app_data: &AppData) -> Result<String, BucketError> {
+    eprintln!("downloading game manifest...");
+
+    let auth = app_data.auth.as_ref().expect("required auth data");
+
+    let url = auth.endpoint(&format!("/api/v1/client/game/manifest?id={}&version={}", params.0, params.1));
+    let client = http::client();
+    let response = http::send_retrying_connect(client.get(url).header("Authorization", generate_authorization_header(auth)))?;
+
+    let status = response.status();
+    if status == 401 || status == 403 {
+        return Err(BucketError::Auth(format!("{} turned down the manifest request with {status}", auth.remote)));
+    }
+    if status != 200 {
+        return Err(BucketError::ServerError { status, body: response.text()? });
     }
 
-    let manifest = response.json::<DropManifest>().expect("failed to parse manifest");
+    Ok(http::read_manifest_body(response)?)
+}
+
+fn fetch_manifest(params: (String, String), app_data: &AppData) -> Result<DropManifest, BucketError> {
+    Ok(serde_json::from_str::<UniqueManifest>(&fetch_manifest_text(params, app_data)?)?.0)
+}
+
+// Along with the lock that keeps a download to this manifest, should the server republish the version partway through
+fn fetch_locked_manifest(params: (String, String), app_data: &AppData) -> Result<(DropManifest, ManifestLock), BucketError> {
+    let version = params.1.clone();
+    let text = fetch_manifest_text(params, app_data)?;
+    let hash = hex::encode(digest(&SHA256, text.as_bytes()));
+    Ok((serde_json::from_str::<UniqueManifest>(&text)?.0, ManifestLock { version, hash }))
+}
 
-    return manifest;
+// Exits with the code for the kind of failure, so scripts can tell them apart
+fn exit_with(e: anyhow::Error) -> ! {
+    eprintln!("{e:#}");
+    std::process::exit(error::exit_code(&e));
 }
 
 fn main() {
     let mut args = Args::parse();
+    http::configure(&args);
+    progress::configure_color(args.no_color);
+    if let Some(Command::Cat { .. }) = args.command {
+        STDOUT_IS_DATA.store(true, Ordering::Relaxed);
+    }
+
+    if args.command.is_none()
+        && args.output_archive.is_none()
+        && let Err(e) = validate_install_dir(&args.install_dir)
+    {
+        eprintln!("can't install to {}: {e}", args.install_dir);
+        std::process::exit(1);
+    }
+    if let Some(archive) = &args.output_archive
+        && let Err(e) = validate_install_dir(&archive_dir(archive, args.tmp_dir.as_deref()))
+    {
+        eprintln!("can't download for {archive} into {}: {e}", archive_dir(archive, args.tmp_dir.as_deref()));
+        std::process::exit(1);
+    }
+    // Otherwise it holds the archive's files, and there's no install to stage
+    if let Some(tmp_dir) = &args.tmp_dir
+        && args.command.is_none()
+        && args.output_archive.is_none()
+    {
+        args.staging_dir = Some(tmp_staging_dir(tmp_dir, &args.install_dir));
+    }
+    if args.files_from.as_deref() == Some("-") && !args.silent {
+        eprintln!("--files-from - reads stdin, so it needs --silent to keep prompts from reading it too");
+        std::process::exit(1);
+    }
+    if let Some(staging_dir) = &args.staging_dir {
+        if args.update {
+            eprintln!("--staging-dir needs a full download, so it can't be combined with --update");
+            std::process::exit(1);
+        }
+        if let Err(e) = validate_install_dir(staging_dir) {
+            eprintln!("can't stage in {staging_dir}: {e}");
+            std::process::exit(1);
+        }
+        match same_filesystem(Path::new(staging_dir), Path::new(&args.install_dir)) {
+            Ok(true) => {}
+            Ok(false) => println!("{staging_dir} is on a different filesystem to {}, so it'll be copied into place rather than renamed", args.install_dir),
+            Err(e) => println!("couldn't tell whether {staging_dir} is on the same filesystem as {} ({e}), it'll be copied into place if it isn't", args.install_dir),
+        }
+    }
+
+    // Anything that only looks at local state doesn't need auth
+    if let Some(Command::Status) = args.command {
+        status(&args);
+        return;
+    }
+    // Reports on auth rather than going through it
+    if let Some(Command::Doctor) = args.command {
+        doctor(&args);
+        return;
+    }
+
+    let mut app_data = read_app_data(args.silent);
+    // Remembered in bucket.json, and auth is moved over when it's saved below
+    if args.credentials_file.is_some() {
+        app_data.credentials = args.credentials_file.clone();
+    }
+
+    // Changing it on an existing auth, as it's the same server either way
+    if let (Some(api_prefix), Some(auth)) = (&args.api_prefix, &mut app_data.auth) {
+        auth.api_prefix = api_prefix.clone();
+    }
 
-    let mut app_data = read_app_data();
+    let link_server = match &args.target {
+        Some(Target::Link(link)) => Some(link.server.clone()),
+        _ => None,
+    };
+    // Set as though they came from the flags, so fetch_params takes them as given
+    match &args.target {
+        Some(Target::Link(link)) => {
+            if link.version.is_some() && args.target_version.is_some() {
+                eprintln!("the link already names a version, so it can't be given again");
+                std::process::exit(1);
+            }
+            args.game = Some(link.game.clone());
+            args.game_version = link.version.clone().or(args.target_version.clone()).or(args.game_version.clone());
+        }
+        Some(Target::Game(game)) => {
+            args.game = Some(game.clone());
+            args.game_version = args.target_version.clone().or(args.game_version.clone());
+        }
+        None => {}
+    }
+    // Compared by host alone, so an existing http:// auth for the same server is kept
+    if let (Some(server), Some(auth)) = (&link_server, &app_data.auth)
+        && (auth.remote.host_str(), auth.remote.port()) != (server.host_str(), server.port())
+    {
+        shitty_write(&mut console(), format!("link is for {server}, but bucket is authenticated with {}, authenticating again\n", auth.remote));
+        app_data.auth = None;
+    }
 
     while app_data.auth.is_none() {
         if args.silent {
-            panic!("silent mode enabled but interactive auth required");
+            exit_with(BucketError::Auth("silent mode enabled but interactive auth required".to_owned()).into());
         }
-        do_auth(&mut app_data);
+        do_auth(&mut app_data, link_server.clone(), &args).unwrap_or_else(|e| exit_with(anyhow::Error::new(e).context("failed to authenticate")));
     }
     save_app_data(&app_data);
 
-    let params = fetch_params(&mut args);
+    // Only needs auth, not a game
+    if let Some(Command::RotateCert) = args.command {
+        rotate_cert(&mut app_data).unwrap_or_else(|e| exit_with(anyhow::Error::new(e).context("failed to rotate certificate")));
+        return;
+    }
+
+    if args.command.is_none() && args.game.is_none() && !args.no_resume {
+        offer_resume(&mut args);
+    }
+    // Following the channel the install came from, unless told otherwise
+    if args.update
+        && args.game_version.is_none()
+        && args.channel.is_none()
+        && let Some(record) = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into())).find(&args.install_dir)
+        && args.game.as_ref().is_none_or(|game| *game == record.game_id)
+    {
+        args.channel = record.channel.clone();
+    }
+
+    let params = resolve_params(&mut args, &app_data);
+
+    // Runs the download with its own thread counts, so it needs args to itself
+    if let Some(Command::Bench { budget, thread_counts }) = &args.command {
+        let (budget, thread_counts) = (*budget, thread_counts.clone());
+        let manifest = fetch_manifest(params.clone(), &app_data).unwrap_or_else(|e| exit_with(e.into()));
+        if let Err(e) = bench(&params.0, &manifest, budget, &thread_counts, &app_data, &mut args) {
+            exit_with(e);
+        }
+        return;
+    }
+
+    match &args.command {
+        Some(Command::Cat { path }) => cat(params, path, &app_data),
+        Some(Command::Files { sort, json }) => list_files(params, *sort, *json, &app_data),
+        Some(Command::Manifest { raw }) => print_manifest(params, *raw, &app_data),
+        Some(Command::Digest) => println!("{}", install_digest(&fetch_manifest(params, &app_data).unwrap_or_else(|e| exit_with(e.into())))),
+        Some(Command::Verify { json }) => verify_install(params, *json, &app_data, &args),
+        Some(Command::Bench { .. }) => unreachable!("bench is handled before the other commands"),
+        Some(Command::Status) => unreachable!("status is handled before auth"),
+        Some(Command::Doctor) => unreachable!("doctor is handled before auth"),
+        Some(Command::RotateCert) => unreachable!("rotate-cert is handled before picking a game"),
+        None => install(params, &app_data, &args),
+    }
+}
+
+// Picks the game and version back up from an interrupted download into the install dir, asking first unless --silent
+fn offer_resume(args: &mut Args) {
+    let state = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into()));
+    let Some(download) = state.interrupted(&args.install_dir) else {
+        return;
+    };
+
+    let question = format!(
+        "the download of {} version {} into {} started {} never finished, resume it?",
+        download.game_id,
+        download.version,
+        download.install_dir,
+        format_timestamp(download.started_at)
+    );
+    if args.silent {
+        println!("resuming the download of {} version {} into {}", download.game_id, download.version, download.install_dir);
+    } else if !confirm(&question) {
+        return;
+    }
+
+    args.game = Some(download.game_id.clone());
+    args.game_version = Some(download.version.clone());
+    args.resuming = true;
+}
+
+fn rotate_cert(app_data: &mut AppData) -> Result<(), BucketError> {
+    let auth = app_data.auth.as_mut().ok_or_else(|| BucketError::Auth("not authenticated".to_owned()))?;
+
+    let endpoint = auth.endpoint("/api/v1/client/auth/rotate");
+    let response = http::send(http::client().post(endpoint).header("Authorization", generate_authorization_header(auth)))?;
+
+    let status = response.status();
+    if status == 404 {
+        return Err(BucketError::ServerError {
+            status,
+            body: format!("{} doesn't support rotating certificates, remove {APP_DATA_PATH} and run bucket again to authenticate from scratch", auth.remote),
+        });
+    }
+    if status != 200 {
+        return Err(BucketError::ServerError { status, body: response.text()? });
+    }
+
+    let response = serde_json::from_str::<RotateCertResponse>(&response.text()?).map_err(|e| BucketError::Auth(format!("failed to parse rotate response: {e}")))?;
+    auth.private = response.private;
+    auth.public = response.certificate;
+    save_app_data(app_data);
+
+    println!("rotated the client certificate for {}", app_data.auth.as_ref().unwrap().remote);
+    Ok(())
+}
+
+fn cat(params: (String, String), path: &str, app_data: &AppData) {
+    let manifest = fetch_manifest(params.clone(), app_data).unwrap_or_else(|e| exit_with(e.into()));
+
+    if let Err(e) = stream_file(&params.0, path, &manifest, app_data) {
+        exit_with(e.context(format!("failed to stream {path}")));
+    }
+}
+
+fn list_files(params: (String, String), sort: FileSort, json: bool, app_data: &AppData) {
+    let manifest = fetch_manifest(params, app_data).unwrap_or_else(|e| exit_with(e.into()));
 
+    let mut files = manifest
+        .into_iter()
+        .map(|(path, chunk)| ManifestFile {
+            path,
+            size: chunk.lengths.iter().sum(),
+            permissions: chunk.permissions,
+        })
+        .collect::<Vec<ManifestFile>>();
+    match sort {
+        FileSort::Path => files.sort_by(|a, b| a.path.cmp(&b.path)),
+        FileSort::Size => files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path))),
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&files).expect("failed to serialize file list"));
+        return;
+    }
+
+    for file in files {
+        println!("{:o}\t{}\t{}", file.permissions, file.size, file.path);
+    }
+}
+
+fn print_manifest(params: (String, String), raw: bool, app_data: &AppData) {
+    let manifest = fetch_manifest_text(params, app_data).unwrap_or_else(|e| exit_with(e.into()));
+
+    if raw {
+        print!("{manifest}");
+        return;
+    }
+
+    let manifest = serde_json::from_str::<serde_json::Value>(&manifest).expect("manifest isn't valid JSON");
+    println!("{}", serde_json::to_string_pretty(&manifest).expect("failed to serialize manifest"));
+}
+
+fn verify_install(params: (String, String), json: bool, app_data: &AppData, args: &Args) {
+    let manifest = fetch_manifest(params, app_data).unwrap_or_else(|e| exit_with(e.into()));
+
+    let mut cache = read_verify_cache(&args.verify_cache);
+    let mut failed = verify(&args.install_dir, &manifest, &mut cache, args.verify_permissions);
+    save_verify_cache(&args.verify_cache, &cache);
+    if args.repair {
+        failed = repair_permissions(&args.install_dir, failed);
+    }
+
+    let mut state = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into()));
+    if let Some(record) = state.find_mut(&args.install_dir) {
+        record.verified_at = Some(Utc::now().timestamp_millis());
+        record.verify_passed = Some(failed.is_empty());
+        save_install_state(&args.state_file, &state).unwrap_or_else(|e| exit_with(e.into()));
+    }
+
+    if json {
+        let report = serde_json::json!({
+            "files": manifest.len(),
+            "verified": manifest.len() - failed.len(),
+            "failed": failed,
+        });
+        println!("{}", serde_json::to_string_pretty(&report).expect("failed to serialize verify report"));
+    } else {
+        for failure in &failed {
+            print_verify_failure(failure);
+        }
+        println!("{} of {} files verified", manifest.len() - failed.len(), manifest.len());
+    }
+
+    if !failed.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn print_verify_failure(failure: &VerifyFailure) {
+    if let Some(expected_target) = &failure.expected_target {
+        println!("{}: expected a symlink to {expected_target}, got {}", failure.path, failure.actual_target.as_deref().unwrap_or("<none>"));
+        return;
+    }
+    let Some(actual_size) = failure.actual_size else {
+        println!("{}: missing, expected {} bytes", failure.path, failure.expected_size);
+        return;
+    };
+
+    if !failure.mode_only() {
+        println!("{}: expected {} bytes, got {actual_size}", failure.path, failure.expected_size);
+        for chunk in &failure.chunks {
+            println!("  chunk {}: expected {}, got {}", chunk.index, chunk.expected, chunk.actual.as_deref().unwrap_or("<truncated>"));
+        }
+    }
+    if let (Some(expected_mode), Some(actual_mode)) = (failure.expected_mode, failure.actual_mode) {
+        println!("{}: expected mode {expected_mode:o}, got {actual_mode:o}", failure.path);
+    }
+}
+
+fn format_timestamp(millis: i64) -> String {
+    DateTime::from_timestamp_millis(millis).map(|time| time.to_rfc3339()).unwrap_or_else(|| "<invalid>".to_owned())
+}
+
+fn status(args: &Args) {
+    let state = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into()));
+    let Some(record) = state.find(&args.install_dir) else {
+        let contents = if Path::new(&args.install_dir).is_dir() { "has files, but" } else { "doesn't exist, and" };
+        println!("{}: unknown, it {contents} no install is recorded for it in {}", args.install_dir, args.state_file);
+        return;
+    };
+
+    println!("{}: game {} version {}, installed {}", record.install_dir, record.game_id, record.version, format_timestamp(record.completed_at));
+    match (record.verify_passed, record.verified_at) {
+        (Some(passed), Some(verified_at)) => println!("last verified {}: {}", format_timestamp(verified_at), if passed { "passed" } else { "failed" }),
+        _ => println!("never verified"),
+    }
+}
+
+// Signed requests carry a timestamp, which the server checks against its own clock
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+// Prints a line of the doctor checklist, returning whether it passed
+fn doctor_check(name: &str, result: Result<String, String>) -> bool {
+    match result {
+        Ok(detail) => {
+            println!("[ok]   {name}: {detail}");
+            true
+        }
+        Err(hint) => {
+            println!("[fail] {name}: {hint}");
+            false
+        }
+    }
+}
+
+fn doctor(args: &Args) {
+    let mut passed = doctor_check(
+        "install dir",
+        validate_install_dir(&args.install_dir)
+            .and_then(|()| Ok(fs4::available_space(&args.install_dir)?))
+            .map(|available| format!("{} is writable with {} free", args.install_dir, HumanBytes(available)))
+            .map_err(|e| format!("{} can't be installed to, {e}", args.install_dir)),
+    );
+
+    let app_data = match fs::read_to_string(APP_DATA_PATH) {
+        Ok(contents) => parse_app_data(&contents).map_err(|e| format!("{APP_DATA_PATH} is broken, {e}. Run bucket to fix it or authenticate again")),
+        Err(e) => Err(format!("can't read {APP_DATA_PATH}, {e}. Run bucket to authenticate")),
+    };
+    let auth = app_data.and_then(|app_data| app_data.auth.ok_or_else(|| format!("{APP_DATA_PATH} has no auth saved, run bucket to authenticate")));
+    passed &= doctor_check("auth", auth.as_ref().map(|auth| format!("saved for {}", auth.remote)).map_err(String::clone));
+    let Ok(auth) = auth else {
+        std::process::exit(1);
+    };
+
+    let client = http::client();
+    let response = http::send(client.get(auth.remote.clone()));
+    passed &= doctor_check(
+        "server",
+        response
+            .as_ref()
+            .map(|response| format!("{} responded with {}", auth.remote, response.status()))
+            .map_err(|e| format!("can't reach {}, {e}. Check the URL and your network", auth.remote)),
+    );
+
+    if let Ok(response) = &response {
+        let server_time = response.headers().get("Date").and_then(|date| date.to_str().ok()).and_then(|date| DateTime::parse_from_rfc2822(date).ok());
+        passed &= doctor_check(
+            "clock",
+            match server_time {
+                Some(server_time) => {
+                    let skew = (Utc::now() - server_time.with_timezone(&Utc)).abs().to_std().unwrap_or_default();
+                    if skew <= MAX_CLOCK_SKEW {
+                        Ok(format!("within {}s of the server", skew.as_secs()))
+                    } else {
+                        Err(format!("{}s off from the server, which can get signed requests rejected. Sync your clock", skew.as_secs()))
+                    }
+                }
+                None => Ok("the server didn't send its time, so skew couldn't be checked".to_owned()),
+            },
+        );
+
+        // Any authenticated endpoint will do, all that matters is whether the signature got through
+        let endpoint = auth.endpoint("/api/v1/client/game/versions?id=");
+        let response = http::send(client.get(endpoint).header("Authorization", generate_authorization_header(&auth)));
+        passed &= doctor_check(
+            "signature",
+            match response {
+                Ok(response) if matches!(response.status().as_u16(), 401 | 403) => Err(format!("the server rejected it with {}. Run bucket rotate-cert, or remove {APP_DATA_PATH} to authenticate again", response.status())),
+                Ok(_) => Ok("accepted by the server".to_owned()),
+                Err(e) => Err(format!("failed to send a signed request, {e}")),
+            },
+        );
+    }
+
+    if !passed {
+        std::process::exit(1);
+    }
+}
+
+fn install(params: (String, String), app_data: &AppData, args: &Args) {
     println!("downloading GAMEID: {}, VERSION: {}", params.0, params.1);
 
     println!("fetching manifest...");
-    let manifest = fetch_manifest(params.clone(), &app_data);
+    let (manifest, lock) = fetch_locked_manifest(params.clone(), app_data).unwrap_or_else(|e| exit_with(e.into()));
     println!("downloaded manifest");
 
+    // Filtered before anything else sees it, so the state file only records what was actually installed
+    let manifest = if args.select_files && !args.silent { select_files(manifest) } else { manifest };
+    let manifest = match &args.files_from {
+        Some(source) => files_from(manifest, source),
+        None => manifest,
+    };
+    // Recorded whole, since what the earlier run did download is just as installed as what's retried now
+    let installed_manifest = manifest.clone();
+    let manifest = match &args.resume_from_report {
+        Some(report) => failed_from_report(manifest, report, &params),
+        None => manifest,
+    };
+
+    let state = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into()));
+
+    let changed = if args.update {
+        let Some(installed) = state.find(&args.install_dir) else {
+            eprintln!("no install recorded for {} in {}, run without --update", args.install_dir, args.state_file);
+            std::process::exit(1);
+        };
+        let changed = installed.changed_files(&manifest);
+        println!("updating from {} to {}, {} of {} files changed", installed.version, params.1, changed.len(), manifest.len());
+        changed
+    } else {
+        manifest.clone()
+    };
+
+    let installed = match state.find(&args.install_dir) {
+        _ if !args.trust_state => HashSet::new(),
+        None => {
+            println!("no install recorded for {} in {}, so there's no state to trust", args.install_dir, args.state_file);
+            HashSet::new()
+        }
+        Some(record) if record.verify_passed == Some(false) => {
+            println!("{} failed its last verify, so its recorded state isn't trusted", args.install_dir);
+            HashSet::new()
+        }
+        Some(record) => record.installed_chunks(&changed),
+    };
+    let needed = |path: &String, index: usize| !installed.contains(&(path.clone(), index));
+
+    let archive_dir = args.output_archive.as_deref().map(|archive| archive_dir(archive, args.tmp_dir.as_deref()));
+    let target_dir = archive_dir.as_ref().or(args.staging_dir.as_ref()).unwrap_or(&args.install_dir);
+
+    if args.dry_run {
+        dry_run(&params.0, target_dir, &changed, &installed, args);
+        return;
+    }
+
+    // Asked before anything touches the disk, so a mistyped game id costs nothing
+    if !args.silent && !args.yes {
+        let files = changed.iter().filter(|(path, chunk)| chunk.symlink.is_none() && (0..chunk.lengths.len()).any(|index| needed(path, index))).count();
+        let size = changed
+            .iter()
+            .filter(|(_, chunk)| chunk.symlink.is_none())
+            .flat_map(|(path, chunk)| chunk.lengths.iter().enumerate().filter(|(index, _)| needed(path, *index)).map(|(_, length)| length))
+            .sum::<usize>();
+        if !confirm(&format!("download {files} files, {}?", HumanBytes(size as u64))) {
+            std::process::exit(1);
+        }
+    }
+
+    if let Err(e) = check_free_space(target_dir, &changed, args.min_free_space.unwrap_or(0)) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    let download_contexts = create_download_contexts(&params.0, &changed, app_data, &args.state_file, Some(&lock)).unwrap_or_else(|e| exit_with(e));
+
+    if args.no_resume
+        && let Err(e) = truncate_files(target_dir, &changed)
+    {
+        exit_with(anyhow::Error::new(e).context(format!("failed to empty the files in {target_dir} for a fresh download")));
+    }
+
     println!("generating buckets...");
-    let buckets = generate_buckets(params.0.clone(), &args.install_dir, &manifest);
+    let mut buckets = generate_buckets(params.0.clone(), target_dir, &changed, &download_contexts, args.dir_mode).unwrap_or_else(|e| exit_with(e.into()));
+    let bucket_bytes = |buckets: &[DownloadBucket]| buckets.iter().flat_map(|bucket| &bucket.drops).map(|drop| drop.length).sum::<usize>();
+    let planned_bytes = bucket_bytes(&buckets);
+    if !installed.is_empty() {
+        for bucket in &mut buckets {
+            bucket.drops.retain(|drop| needed(&drop.filename, drop.index));
+        }
+        buckets.retain(|bucket| !bucket.drops.is_empty());
+        println!("trusting the state file for {} already installed chunks", installed.len());
+    }
+    if args.resuming {
+        let before = buckets.iter().map(|bucket| bucket.drops.len()).sum::<usize>();
+        for bucket in &mut buckets {
+            bucket.drops.retain(|drop| !drop_is_valid(drop).unwrap_or(false));
+        }
+        buckets.retain(|bucket| !bucket.drops.is_empty());
+        println!("resuming, {} chunks were already downloaded", before - buckets.iter().map(|bucket| bucket.drops.len()).sum::<usize>());
+    }
+    // Whatever the passes above skipped is already done, so progress starts from there rather than 0%
+    let mut stats = DownloadStats {
+        complete_bytes: planned_bytes - bucket_bytes(&buckets),
+        ..Default::default()
+    };
     println!("generated {} buckets", buckets.len());
 
+    order_buckets(&mut buckets, args.order, &priority_patterns(args));
+    if let Some(path) = &args.dump_plan {
+        save_plan(path, &DownloadPlan::new(&params.0, &buckets));
+    }
+
+    let mut state = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into()));
+    state.start_download(&params.0, &params.1, &args.install_dir);
+    save_install_state(&args.state_file, &state).unwrap_or_else(|e| exit_with(e.into()));
+
     println!("downloading game...");
-    download(params.0, buckets, &app_data, &args);
+    let started_at = Utc::now().timestamp_millis();
+    let result = download(params.0.clone(), buckets, &download_contexts, app_data, args, Arc::new(AtomicBool::new(false)), &mut stats).and_then(|()| finish_install(&params.0, &manifest, app_data, args));
+
+    if let Some(report) = &args.report {
+        save_report(report, &DownloadReport::new(&params, &args.install_dir, started_at, stats, result.as_ref().err().map(|e| format!("{e:#}"))));
+    }
+    if let Err(e) = result {
+        exit_with(e);
+    }
+    println!("install digest: {}", install_digest(&installed_manifest));
+
+    // A version typed in at the prompt wins over --channel, and then there's no channel to follow
+    let channel = args.channel.clone().filter(|_| args.game_version.is_none());
+    // Read again, since contexts were saved to it during the download
+    let mut state = read_install_state(&args.state_file).unwrap_or_else(|e| exit_with(e.into()));
+    state.finish_download(&args.install_dir);
+    // An archive isn't an install, so there's nothing to record
+    if args.output_archive.is_none() {
+        state.record(InstallRecord::new(params.0, params.1, args.install_dir.clone(), &installed_manifest, Some(lock.hash), channel));
+    }
+    save_install_state(&args.state_file, &state).unwrap_or_else(|e| exit_with(e.into()));
+}
+
+// Reads what's on disk but writes nothing. Buckets are split without download contexts, so their count is an estimate
+// if the server takes fewer files per chunk request than the default.
+fn dry_run(game_id: &str, target_dir: &str, manifest: &DropManifest, installed: &HashSet<(String, usize)>, args: &Args) {
+    let mut buckets = plan_buckets(game_id.to_owned(), target_dir, manifest, &HashMap::new());
+    if let Some(path) = &args.dump_plan {
+        order_buckets(&mut buckets, args.order, &priority_patterns(args));
+        save_plan(path, &DownloadPlan::new(game_id, &buckets));
+    }
+
+    let (mut complete, mut complete_size, mut remaining_size) = (0, 0, 0);
+    for bucket in &buckets {
+        let mut bucket_complete = true;
+        for drop in &bucket.drops {
+            if installed.contains(&(drop.filename.clone(), drop.index)) || drop_is_valid(drop).unwrap_or(false) {
+                complete_size += drop.length;
+            } else {
+                remaining_size += drop.length;
+                bucket_complete = false;
+            }
+        }
+        if bucket_complete {
+            complete += 1;
+        }
+    }
+
+    println!("{complete} of {} buckets are already complete ({})", buckets.len(), HumanBytes(complete_size as u64));
+    println!("{} buckets left to download ({})", buckets.len() - complete, HumanBytes(remaining_size as u64));
+}
+
+fn priority_patterns(args: &Args) -> Vec<Pattern> {
+    args.priority.iter().map(|pattern| Pattern::new(pattern).expect("failed to parse priority glob")).collect()
+}
+
+// Keeps exactly the manifest paths listed, one per line, in source (a file, or - for stdin)
+fn files_from(mut manifest: DropManifest, source: &str) -> DropManifest {
+    let contents = if source == "-" { io::read_to_string(io::stdin()) } else { fs::read_to_string(source) };
+    let contents = contents.unwrap_or_else(|e| exit_with(anyhow::Error::new(BucketError::Io(e)).context(format!("failed to read file list from {source}"))));
+    let paths = contents.lines().map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty()).collect::<HashSet<&str>>();
+
+    let mut missing = paths.iter().filter(|path| !manifest.contains_key(**path)).collect::<Vec<_>>();
+    if !missing.is_empty() {
+        missing.sort();
+        for path in missing {
+            eprintln!("{path} isn't in the manifest");
+        }
+        std::process::exit(1);
+    }
+
+    manifest.retain(|path, _| paths.contains(path.as_str()));
+    manifest
+}
+
+// Keeps the files the report's run failed to download, so a second pass only has those to fetch
+fn failed_from_report(mut manifest: DropManifest, path: &str, params: &(String, String)) -> DropManifest {
+    let report = read_report(path).unwrap_or_else(|e| {
+        eprintln!("failed to read report {path}: {e}");
+        std::process::exit(1);
+    });
+    if (&report.game_id, &report.version) != (&params.0, &params.1) {
+        eprintln!("{path} is a report for {} {}, not {} {}", report.game_id, report.version, params.0, params.1);
+        std::process::exit(1);
+    }
+    if report.failed_files.is_empty() {
+        println!("{path} has no failed files to retry");
+        std::process::exit(0);
+    }
+
+    // The manifest may have moved on since, and a file that's gone from it can't be retried anyway
+    for missing in report.failed_files.iter().filter(|path| !manifest.contains_key(*path)) {
+        println!("{missing} isn't in the manifest anymore, skipping it");
+    }
+    let failed = report.failed_files.iter().collect::<HashSet<_>>();
+    manifest.retain(|path, _| failed.contains(path));
+    println!("retrying {} files from {path}", manifest.len());
+    manifest
+}
+
+// Reads every file back, since chunks that verified as they downloaded can still end up wrong on disk.
+// Staged and archived downloads are already verified before they're used, so this is for everything else.
+fn post_verify(game_id: &str, manifest: &DropManifest, app_data: &AppData, args: &Args) -> Result<(), anyhow::Error> {
+    println!("verifying {}...", args.install_dir);
+    let mut cache = read_verify_cache(&args.verify_cache);
+    // --set-mtime gives a rewritten file the mtime it had when it last verified, so only hashing can tell
+    if args.set_mtime.is_some() {
+        cache.forget(&args.install_dir, manifest);
+    }
+    let mut failed = verify(&args.install_dir, manifest, &mut cache, args.verify_permissions);
+    if args.repair {
+        failed = repair_permissions(&args.install_dir, failed);
+    }
+
+    if !failed.is_empty() && args.repair {
+        println!("{} files failed verification, downloading them again...", failed.len());
+        let broken = failed.iter().map(|failure| (failure.path.clone(), manifest[&failure.path].clone())).collect::<DropManifest>();
+        let download_contexts = create_download_contexts(game_id, &broken, app_data, &args.state_file, None)?;
+        let buckets = generate_buckets(game_id.to_owned(), &args.install_dir, &broken, &download_contexts, args.dir_mode)?;
+        download(game_id.to_owned(), buckets, &download_contexts, app_data, args, Arc::new(AtomicBool::new(false)), &mut DownloadStats::default())?;
+        // Files written from scratch get the default mode, so they may need fixing too
+        failed = repair_permissions(&args.install_dir, verify(&args.install_dir, &broken, &mut cache, args.verify_permissions));
+    }
+    save_verify_cache(&args.verify_cache, &cache);
+
+    for failure in &failed {
+        print_verify_failure(failure);
+    }
+    if !failed.is_empty() {
+        let hint = if args.repair { "" } else { ", run again with --repair to download them again" };
+        return Err(anyhow!("{} files failed verification after downloading{hint}", failed.len()));
+    }
+
+    Ok(())
+}
+
+// The first component of a manifest path, or None for files at the root
+fn top_level(path: &str) -> Option<&str> {
+    path.split_once('/').map(|(component, _)| component)
+}
+
+// A numbered menu of the manifest's top level directories, keeping only the files under the chosen ones
+fn select_files(mut manifest: DropManifest) -> DropManifest {
+    let mut components = BTreeMap::<Option<&str>, (usize, usize)>::new();
+    for (path, chunk) in &manifest {
+        let (files, size) = components.entry(top_level(path)).or_default();
+        *files += 1;
+        *size += chunk.lengths.iter().sum::<usize>();
+    }
+    let components = components.into_iter().collect::<Vec<_>>();
+
+    for (index, (component, (files, size))) in components.iter().enumerate() {
+        println!("{:>3}) {}/ - {files} files, {}", index + 1, component.unwrap_or("."), HumanBytes(*size as u64));
+    }
+
+    let mut lines = io::stdin().lock().lines();
+    let mut console = console();
+    let selected = loop {
+        shitty_write(&mut console, "install which (e.g. 1,3) [all]: ".to_owned());
+        let answer = read_input(&mut lines);
+        if answer.is_empty() || answer == "all" {
+            return manifest;
+        }
+
+        let selected = answer
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|number| !number.is_empty())
+            .map(|number| number.parse::<usize>().ok().filter(|number| (1..=components.len()).contains(number)))
+            .collect::<Option<HashSet<usize>>>();
+        match selected {
+            Some(selected) => break selected,
+            None => println!("expected numbers between 1 and {}", components.len()),
+        }
+    };
+
+    let selected = selected.into_iter().map(|number| components[number - 1].0.map(str::to_owned)).collect::<HashSet<Option<String>>>();
+    manifest.retain(|path, _| selected.contains(&top_level(path).map(str::to_owned)));
+    manifest
+}
+
+// Everything between the download finishing and the install being recorded
+fn finish_install(game_id: &str, manifest: &DropManifest, app_data: &AppData, args: &Args) -> Result<(), anyhow::Error> {
+    if let Some(archive) = &args.output_archive {
+        let archive_dir = archive_dir(archive, args.tmp_dir.as_deref());
+        println!("verifying {archive_dir} before packing it into {archive}...");
+        let failed = verify(&archive_dir, manifest, &mut VerifyCache::default(), false);
+        if !failed.is_empty() {
+            return Err(anyhow!("{} files in {archive_dir} failed verification, not writing {archive}", failed.len()));
+        }
+        pack_archive(&archive_dir, manifest, archive).map_err(|e| anyhow!("failed to write {archive}: {e}"))?;
+        fs::remove_dir_all(&archive_dir)?;
+        println!("wrote {archive}");
+        return Ok(());
+    }
+
+    if let Some(staging_dir) = &args.staging_dir {
+        println!("verifying {staging_dir} before moving it into place...");
+        // Staging paths are transient, so they stay out of the persistent cache
+        let failed = verify(staging_dir, manifest, &mut VerifyCache::default(), false);
+        if !failed.is_empty() {
+            return Err(anyhow!("{} files in {staging_dir} failed verification, leaving {} untouched", failed.len(), args.install_dir));
+        }
+        commit_staging(staging_dir, &args.install_dir).map_err(|e| anyhow::Error::new(BucketError::Io(e)).context(format!("failed to move {staging_dir} into place at {}", args.install_dir)))?;
+    } else if !args.no_post_verify && !args.no_verify {
+        post_verify(game_id, manifest, app_data, args)?;
+    }
+
+    if args.check_sizes && !check_sizes(&args.install_dir, manifest) {
+        return Err(anyhow!("{} doesn't match the manifest's sizes", args.install_dir));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn game_version(name: &str, channels: &[&str]) -> GameVersion {
+        GameVersion {
+            game_id: "game".to_owned(),
+            version_name: name.to_owned(),
+            channels: channels.iter().map(|channel| channel.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn no_versions() {
+        let e = pick_version("game", LATEST_CHANNEL, &[]).unwrap_err();
+        assert_eq!(e.to_string(), "no versions available for game game");
+        assert!(pick_version("game", "beta", &[]).is_err());
+    }
+
+    #[test]
+    fn newest_version_on_channel() {
+        let versions = [game_version("3", &["beta"]), game_version("2", &["Stable"]), game_version("1", &[])];
+        assert_eq!(pick_version("game", LATEST_CHANNEL, &versions).unwrap(), "3");
+        assert_eq!(pick_version("game", "beta", &versions).unwrap(), "3");
+        assert_eq!(pick_version("game", "stable", &versions).unwrap(), "2");
+        assert!(pick_version("game", "nightly", &versions).is_err());
+    }
 }
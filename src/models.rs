@@ -1,7 +1,19 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{collections::HashMap, fmt, num::NonZeroUsize, path::PathBuf, str::FromStr};
 
-use clap::{Parser, arg};
-use serde::{Deserialize, Serialize};
+use clap::{Parser, Subcommand, ValueEnum, builder::RangedU64ValueParser};
+use reqwest::{
+    Url,
+    header::{HeaderName, HeaderValue},
+};
+use serde::{
+    Deserialize, Deserializer, Serialize,
+    de::{self, MapAccess, Visitor},
+};
+
+use crate::{
+    download::MAX_FILES_PER_BUCKET,
+    download_internals::{MAX_PACKET_LENGTH, WRITE_BUFFER_SIZE},
+};
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -24,6 +36,63 @@ pub struct HandshakeResponse {
     pub private: String,
     pub certificate: String,
     pub id: String,
+    /// The subset of our advertised capabilities the server agreed to, older servers don't send it
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RotateCertResponse {
+    pub private: String,
+    pub certificate: String,
+}
+
+// Features that need both sides to agree on them, advertised at initiate
+// The keys are reserved ahead of the features themselves, which is why some variants aren't constructed yet
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Manifest entries may be symlinks
+    Symlink,
+    /// Chunks may be zstd compressed
+    Zstd,
+    /// Chunk requests may ask for a byte range of a chunk
+    Range,
+    /// Manifests may be requested as a delta against an installed version
+    Delta,
+    /// Chunks may be AES-CTR encrypted, with the key in the download context
+    Encryption,
+}
+
+impl Capability {
+    pub fn key(&self) -> &'static str {
+        match self {
+            Capability::Symlink => "symlink",
+            Capability::Zstd => "zstd",
+            Capability::Range => "range",
+            Capability::Delta => "delta",
+            Capability::Encryption => "encryption",
+        }
+    }
+
+    // Only what this build actually implements, so the server never sends something we can't handle
+    pub fn supported() -> Vec<Capability> {
+        let mut capabilities = Vec::new();
+        if cfg!(unix) {
+            capabilities.push(Capability::Symlink);
+        }
+        capabilities.push(Capability::Range);
+        capabilities.push(Capability::Encryption);
+        capabilities
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GameSearchResult {
+    pub id: String,
+    pub name: String,
 }
 
 #[derive(Deserialize)]
@@ -31,6 +100,9 @@ pub struct HandshakeResponse {
 pub struct GameVersion {
     pub game_id: String,
     pub version_name: String,
+    /// Channels this is the current version on, from servers that have them
+    #[serde(default)]
+    pub channels: Vec<String>,
 }
 
 impl std::ops::Deref for GameVersion {
@@ -42,6 +114,40 @@ impl std::ops::Deref for GameVersion {
 }
 
 pub type DropManifest = HashMap<String, DropChunk>;
+
+// Deserializing straight into a DropManifest keeps the last of any repeated path without a word, which could
+// quietly lose a file. Repeats that say the same thing are only warned about, anything else is an error.
+pub struct UniqueManifest(pub DropManifest);
+
+impl<'de> Deserialize<'de> for UniqueManifest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ManifestVisitor;
+
+        impl<'de> Visitor<'de> for ManifestVisitor {
+            type Value = UniqueManifest;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of paths to chunks")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut manifest = DropManifest::with_capacity(map.size_hint().unwrap_or(0));
+                while let Some((path, chunk)) = map.next_entry::<String, DropChunk>()? {
+                    match manifest.get(&path) {
+                        Some(existing) if *existing == chunk => eprintln!("{path} is in the manifest more than once, all the same"),
+                        Some(_) => return Err(de::Error::custom(format!("{path} is in the manifest more than once, with different contents"))),
+                        None => {
+                            manifest.insert(path, chunk);
+                        }
+                    }
+                }
+                Ok(UniqueManifest(manifest))
+            }
+        }
+
+        deserializer.deserialize_map(ManifestVisitor)
+    }
+}
 #[derive(Serialize, Deserialize, Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct DropChunk {
@@ -50,27 +156,515 @@ pub struct DropChunk {
     pub checksums: Vec<String>,
     pub lengths: Vec<usize>,
     pub version_name: String,
+    /// Set when the entry is a symlink, to the link's target. Symlinks carry no content, so lengths and checksums are ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<String>,
+    /// Modification time in seconds since the unix epoch, for servers that record one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// ID of game to download, the same as --game, or a drop://server/game/version link to authenticate with
+    /// and download from, the version is optional
+    #[arg(value_name = "GAME_OR_LINK", conflicts_with = "game")]
+    pub target: Option<Target>,
+
+    /// Version of game to download, the same as --game-version
+    #[arg(value_name = "VERSION", requires = "target", conflicts_with_all = ["game_version", "channel"])]
+    pub target_version: Option<String>,
+
     /// ID of game to download
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub game: Option<String>,
 
     /// Version of game to download, defaults to latest
-    #[arg(long, short = 'k')]
+    #[arg(long, short = 'k', global = true)]
     pub game_version: Option<String>,
 
-    #[arg(long, default_value_t = format!("./game"))]
+    /// Download the version currently on this channel, like stable or beta, rather than a fixed one. latest is
+    /// always the newest version. Recorded with the install, so --update keeps following it
+    // target_version only exists on the top level command, so it names channel rather than the other way round
+    #[arg(long, global = true, conflicts_with = "game_version")]
+    pub channel: Option<String>,
+
+    /// Where the game is installed. The flag wins over BUCKET_INSTALL_DIR, which wins over ./game
+    #[arg(long, env = "BUCKET_INSTALL_DIR", default_value_t = format!("./game"), global = true)]
     pub install_dir: String,
 
-    #[arg(long, short)]
+    #[arg(long, short, global = true)]
     pub silent: bool,
 
+    /// Start downloading without asking to confirm the size first, implied by --silent
+    #[arg(long, short)]
+    pub yes: bool,
+
+    /// Check how much of the download is already complete on disk and report what's left, without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
     #[arg(long, short, default_value_t = 4)]
     pub threads: usize,
+
+    /// Download one bucket at a time, in the same order every run, so logs show exactly which bucket did what
+    #[arg(long, conflicts_with = "threads")]
+    pub serial: bool,
+
+    /// Bytes read from the server at a time
+    #[arg(long, default_value_t = MAX_PACKET_LENGTH, value_parser = RangedU64ValueParser::<usize>::new().range(4096..=16 * 1024 * 1024))]
+    pub read_buffer: usize,
+
+    /// Bytes buffered per file before writing to disk
+    #[arg(long, default_value_t = WRITE_BUFFER_SIZE, value_parser = RangedU64ValueParser::<usize>::new().range(4096..=256 * 1024 * 1024))]
+    pub write_buffer: usize,
+
+    /// Most bytes of buffers to have allocated across every bucket at once. Buckets shrink their write buffers
+    /// to fit, and wait for others to finish rather than go over
+    #[arg(long)]
+    pub mem_budget: Option<usize>,
+
+    /// Where completed installs are recorded, for launchers and later runs to read
+    #[arg(long, default_value_t = format!("./bucket-state.json"), global = true)]
+    pub state_file: String,
+
+    /// Where verify remembers files that already passed, to skip re-hashing them
+    #[arg(long, default_value_t = format!("./bucket-verify-cache.json"), global = true)]
+    pub verify_cache: String,
+
+    /// Which IP version to connect to the server over
+    #[arg(long, value_enum, default_value_t = IpVersion::Auto, global = true)]
+    pub ip_version: IpVersion,
+
+    /// Which HTTP version to talk to the server in. HTTP/2 multiplexes every request over one connection per host
+    #[arg(long, value_enum, default_value_t = HttpVersion::Auto, global = true)]
+    pub http_version: HttpVersion,
+
+    /// Testing aid for server feature negotiation: advertise this capability key when authenticating instead of the
+    /// ones this build supports. Repeatable. Nothing checks bucket can handle what the server then sends
+    #[arg(long = "capability", value_name = "KEY", hide_short_help = true)]
+    pub capabilities: Vec<String>,
+
+    /// Testing aid for server feature negotiation: report this as the OS when authenticating
+    #[arg(long, hide_short_help = true)]
+    pub platform: Option<String>,
+
+    /// Where the server's API is mounted, for servers behind a reverse proxy that rewrites paths, like /drop for
+    /// /drop/api/v1/... Remembered with the auth once set
+    #[arg(long, global = true)]
+    pub api_prefix: Option<String>,
+
+    /// Keep auth in this file, only readable by you on unix, with bucket.json just pointing to it. Remembered once
+    /// set, so bucket.json can be shared without the private key
+    #[arg(long, global = true)]
+    pub credentials_file: Option<String>,
+
+    /// Only trust the server if its TLS certificate has this SHA-256 fingerprint, in hex
+    #[arg(long, global = true)]
+    pub pin_cert: Option<CertFingerprint>,
+
+    /// Extra header for every request, as "Name: Value", for servers behind an auth proxy. Can be repeated
+    #[arg(long = "header", value_name = "HEADER", global = true)]
+    pub headers: Vec<ExtraHeader>,
+
+    /// Most connections to open to a single host at once, regardless of --threads
+    #[arg(long, global = true)]
+    pub max_connections_per_host: Option<NonZeroUsize>,
+
+    /// Never style output with colors, which is also the case when NO_COLOR is set or output isn't a terminal
+    #[arg(long, global = true)]
+    pub no_color: bool,
+
+    /// Log every HTTP request and its response status to stderr
+    #[arg(long, global = true)]
+    pub trace_requests: bool,
+
+    /// Order to download buckets in
+    #[arg(long, value_enum, default_value_t = BucketOrder::AsIs)]
+    pub order: BucketOrder,
+
+    /// Glob of paths to download first with --order priority, can be repeated
+    #[arg(long)]
+    pub priority: Vec<String>,
+
+    /// Mint a new download context for every N buckets, rather than sharing one per version
+    #[arg(long)]
+    pub buckets_per_context: Option<NonZeroUsize>,
+
+    /// How to show download progress
+    #[arg(long, value_enum, default_value_t = ProgressMode::Auto)]
+    pub progress: ProgressMode,
+
+    /// Log bytes downloaded, percent complete, speed and ETA every this many seconds, whatever --progress is
+    #[arg(long, value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub stats_interval: Option<u64>,
+
+    /// Skip hashing downloaded data entirely. Faster, but nothing checks the files are intact
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Check each chunk's checksum as soon as it's read, failing its bucket straight away rather than once the bucket is written
+    #[arg(long, conflicts_with = "no_verify")]
+    pub checksum_on_read: bool,
+
+    /// Don't read every file back to verify it once the download finishes
+    #[arg(long)]
+    pub no_post_verify: bool,
+
+    /// Download any files that fail the post download verify again, once. Files whose only problem is their
+    /// permissions just have them fixed, here and with the verify command
+    #[arg(long, conflicts_with = "no_post_verify")]
+    pub repair: bool,
+
+    /// Have verifying also check that file permissions match the manifest's (unix only)
+    #[arg(long)]
+    pub verify_permissions: bool,
+
+    /// Largest manifest or versions list, in bytes, to accept from the server before giving up on it
+    #[arg(long, default_value_t = 256 * 1024 * 1024)]
+    pub max_manifest_size: u64,
+
+    /// Bytes that have to stay free on the install drive, checked before starting and before every bucket
+    #[arg(long)]
+    pub min_free_space: Option<u64>,
+
+    /// While this file exists, no new buckets are started. Defaults to .bucket-pause in the install dir
+    #[arg(long)]
+    pub pause_file: Option<String>,
+
+    /// Abort the whole download after this many failed attempts, across all buckets
+    #[arg(long)]
+    pub max_total_failures: Option<usize>,
+
+    /// Abort and retry a bucket if it downloads slower than this many bytes per second for --min-speed-window
+    #[arg(long)]
+    pub min_speed: Option<usize>,
+
+    /// Seconds a bucket has to stay under --min-speed before it's aborted
+    #[arg(long, default_value_t = 30, value_parser = RangedU64ValueParser::<u64>::new().range(1..))]
+    pub min_speed_window: u64,
+
+    /// Retry failures by kind: never for 4xx responses other than 429, and the full count for 5xx, timeouts and
+    /// anything else. The kind is logged with every retry
+    #[arg(long)]
+    pub limit_retries_per_error_type: bool,
+
+    /// Carry on with the other buckets when one runs out of retries, listing every file that failed at the end
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Stop the whole download at the first chunk that fails its checksum, without retrying it, naming the file
+    #[arg(long, conflicts_with_all = ["keep_going", "no_verify"])]
+    pub fail_fast: bool,
+
+    /// Check each version's download context with a request for one small chunk before starting, so a bad one
+    /// fails straight away rather than in every bucket. Costs a round trip per version
+    #[arg(long)]
+    pub probe_context: bool,
+
+    /// Request chunks with the same checksum only once per bucket, copying the first into place for the rest
+    #[arg(long)]
+    pub dedupe_within_bucket: bool,
+
+    /// When a file is in use by a running game, wait for it to be closed rather than failing
+    #[arg(long)]
+    pub wait_for_unlock: bool,
+
+    /// Picking up a download the state file says was interrupted, so chunks already on disk are checked and skipped
+    #[arg(skip)]
+    pub resuming: bool,
+
+    /// Octal permissions for directories created in the install dir, like 750. Ignored outside unix
+    #[arg(long, value_parser = parse_dir_mode)]
+    pub dir_mode: Option<u32>,
+
+    /// What to do with partially written files when a download fails
+    #[arg(long, value_enum, default_value_t = OnFailure::Keep)]
+    pub on_failure: OnFailure,
+
+    /// Download into this dir, and only move it into place over install dir once it fully verifies
+    #[arg(long)]
+    pub staging_dir: Option<String>,
+
+    /// Pack the game into a tar here, rather than installing it to install dir
+    #[arg(long, conflicts_with_all = ["staging_dir", "update", "check_sizes"])]
+    pub output_archive: Option<String>,
+
+    /// Download somewhere fast first: plain installs are staged in a dir of their own here, and --output-archive's
+    /// files are kept here until they're packed. Staged installs are renamed into place when this is on the same
+    /// filesystem as install dir, and copied over otherwise
+    #[arg(long, conflicts_with_all = ["staging_dir", "update", "trust_state"])]
+    pub tmp_dir: Option<String>,
+
+    /// Pick which of the game's top level directories to install from a menu, everything is installed with --silent
+    #[arg(long)]
+    pub select_files: bool,
+
+    /// Only install the manifest paths listed in this file, one per line, or - to read them from stdin
+    #[arg(long, conflicts_with = "select_files")]
+    pub files_from: Option<String>,
+
+    /// Only download the files a --keep-going run failed, from the file it wrote with --report
+    #[arg(long, conflicts_with_all = ["select_files", "files_from"])]
+    pub resume_from_report: Option<String>,
+
+    /// Only download files that changed since the install recorded in the state file
+    #[arg(long)]
+    pub update: bool,
+
+    /// Skip chunks the state file recorded as installed with the same checksum, without reading them back.
+    /// Fast, but won't notice anything that got corrupted on disk since. Add --no-post-verify to read nothing at all
+    #[arg(long, alias = "exclude-installed", conflicts_with_all = ["staging_dir", "output_archive"])]
+    pub trust_state: bool,
+
+    /// After downloading, check every file exists and the total size matches the manifest
+    #[arg(long)]
+    pub check_sizes: bool,
+
+    /// Write a JSON summary of the download here once it's over, whether or not it succeeded
+    #[arg(long)]
+    pub report: Option<String>,
+
+    /// Write every bucket, with its version and drops, as JSON here in the order they'll be started. Works with
+    /// --dry-run, where buckets are split as though the server takes the most files per request
+    #[arg(long)]
+    pub dump_plan: Option<String>,
+
+    /// Checkpoint files big enough to get a bucket of their own, and resume them from there rather than from the start
+    #[arg(long)]
+    pub resume_large_files: bool,
+
+    /// Download everything from scratch, emptying every file first, without offering to resume an interrupted
+    /// download or trusting anything the state file says is installed
+    #[arg(long, conflicts_with_all = ["trust_state", "update", "resume_large_files", "resume_from_report"])]
+    pub no_resume: bool,
+
+    /// Give downloaded files a fixed modification time, either seconds since the unix epoch or "manifest" for the manifest's own
+    #[arg(long, value_parser = parse_set_mtime)]
+    pub set_mtime: Option<SetMtime>,
+}
+
+// Servers hand these out to point straight at a game, for "click to install" style links
+// The first positional, told apart by whether it has a scheme, since game IDs never do
+#[derive(Debug, Clone)]
+pub enum Target {
+    Link(ShareLink),
+    Game(String),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.contains("://") {
+            return Ok(Target::Link(value.parse()?));
+        }
+        Ok(Target::Game(value.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ShareLink {
+    /// Always https, since the link has no way to say otherwise
+    pub server: Url,
+    pub game: String,
+    pub version: Option<String>,
+}
+
+impl FromStr for ShareLink {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let link = Url::parse(value).map_err(|e| format!("failed to parse link: {e}"))?;
+        if link.scheme() != "drop" {
+            return Err(format!("expected a drop:// link, got {}://", link.scheme()));
+        }
+
+        let host = link.host_str().ok_or("link has no server")?;
+        let server = match link.port() {
+            Some(port) => format!("https://{host}:{port}"),
+            None => format!("https://{host}"),
+        };
+        let server = Url::parse(&server).map_err(|e| format!("failed to parse link server: {e}"))?;
+
+        let mut segments = link.path_segments().into_iter().flatten().filter(|segment| !segment.is_empty());
+        let game = segments.next().ok_or("link has no game")?.to_owned();
+        let version = segments.next().map(str::to_owned);
+        if segments.next().is_some() {
+            return Err("expected a link like drop://server/game/version".to_owned());
+        }
+
+        Ok(Self { server, game, version })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Write a single file from the game to stdout, without installing anything
+    Cat {
+        /// Path of the file, as it appears in the manifest
+        #[arg(long, short)]
+        path: String,
+    },
+    /// List the files in the game's manifest, without downloading anything
+    Files {
+        #[arg(long, value_enum, default_value_t = FileSort::Path)]
+        sort: FileSort,
+
+        /// Print the listing as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print the game's manifest JSON as the server returned it, without downloading anything
+    Manifest {
+        /// Print the response body byte for byte, rather than pretty-printed with sorted keys
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Print a single digest of everything in the game's manifest, to compare installs or attest to what was downloaded
+    Digest,
+    /// Check the files in the install dir against the game's manifest
+    Verify {
+        /// Print the results, with the details of every failed file, as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Download a sample of the game at several thread counts, report the throughput of each and recommend one. Nothing is kept, and --serial can't be used with it
+    Bench {
+        /// Bytes of the game to download per run
+        #[arg(long, default_value_t = 256 * 1024 * 1024)]
+        budget: u64,
+
+        /// Thread counts to try, comma separated
+        #[arg(long, value_delimiter = ',', default_values_t = [1, 2, 4, 8, 16])]
+        thread_counts: Vec<usize>,
+    },
+    /// Show which game and version the state file has recorded for the install dir
+    Status,
+    /// Check auth, the server, the clock and the install dir, to narrow down why things are failing
+    Doctor,
+    /// Swap the saved client certificate for a freshly issued one, without authenticating again
+    RotateCert,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum BucketOrder {
+    /// Manifest order, by path
+    AsIs,
+    /// Smallest buckets first, so many files complete early
+    SmallFirst,
+    /// Buckets containing a --priority path first
+    Priority,
+    /// Files big enough for a bucket of their own first, largest first, so small buckets fill in around them
+    /// rather than one thread being left with a huge file at the end
+    LargeFirst,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    /// HTTP/2 if the server offers it during the TLS handshake, HTTP/1.1 otherwise
+    Auto,
+    #[value(name = "1.1")]
+    Http1,
+    /// HTTP/2 without negotiating, for testing servers (and plain http ones) known to support it
+    #[value(name = "2")]
+    Http2,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum IpVersion {
+    Auto,
+    V4,
+    V6,
+}
+
+// A SHA-256 certificate fingerprint, parsed from hex with or without the colons openssl puts in
+#[derive(Debug, Clone, Copy)]
+pub struct CertFingerprint(pub [u8; 32]);
+
+impl FromStr for CertFingerprint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(value.replace(':', "")).map_err(|e| format!("expected a hex SHA-256 fingerprint: {e}"))?;
+        let bytes = bytes.try_into().map_err(|bytes: Vec<u8>| format!("expected a SHA-256 fingerprint of 32 bytes, got {}", bytes.len()))?;
+        Ok(Self(bytes))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtraHeader {
+    pub name: HeaderName,
+    pub value: HeaderValue,
+}
+
+impl FromStr for ExtraHeader {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, value) = value.split_once(':').ok_or_else(|| format!("expected \"Name: Value\", got {value}"))?;
+        Ok(Self {
+            name: name.trim().parse().map_err(|e| format!("invalid header name {name}: {e}"))?,
+            value: value.trim().parse().map_err(|e| format!("invalid header value for {name}: {e}"))?,
+        })
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum ProgressMode {
+    /// A bar on terminals, plain lines otherwise
+    Auto,
+    /// A live progress bar
+    Bar,
+    /// A line per finished bucket, for logs
+    Plain,
+    None,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OnFailure {
+    /// Leave them in place, to be resumed later
+    Keep,
+    /// Remove any file a failed bucket was writing
+    Clean,
+}
+
+fn parse_dir_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("expected an octal mode like 755, got {value}"))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SetMtime {
+    Epoch(i64),
+    /// Whatever the manifest has for each file, leaving files it has nothing for alone
+    Manifest,
+}
+
+fn parse_set_mtime(value: &str) -> Result<SetMtime, String> {
+    if value == "manifest" {
+        return Ok(SetMtime::Manifest);
+    }
+    value.parse::<i64>().map(SetMtime::Epoch).map_err(|_| format!("expected seconds since the unix epoch or \"manifest\", got {value}"))
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+pub enum FileSort {
+    Path,
+    Size,
+}
+
+#[derive(Serialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: usize,
+    pub permissions: u32,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -83,6 +677,10 @@ pub struct DownloadDrop {
     pub length: usize,
     pub checksum: String,
     pub permissions: u32,
+    /// Bytes at the start of the drop already on disk from an earlier attempt, which aren't requested again
+    pub offset: usize,
+    /// The file's modification time from the manifest, if it has one
+    pub mtime: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -92,9 +690,45 @@ pub struct DownloadBucket {
     pub drops: Vec<DownloadDrop>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct DownloadContext {
     pub context: String,
+    /// Most files the server takes in one chunk request, older servers don't send it
+    #[serde(default)]
+    pub max_files: Option<NonZeroUsize>,
+    /// Only sent to clients that agreed to the encryption capability, when the server keeps chunks encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<ChunkEncryption>,
+    /// SHA-256 of the manifest the server is serving the context's version from, newer servers send it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_hash: Option<String>,
+    /// Reused from the state file rather than created for this run, so the server may have expired it since
+    #[serde(skip)]
+    pub saved: bool,
+}
+
+// The manifest a download was planned from, which every context for its version has to agree on
+pub struct ManifestLock {
+    pub version: String,
+    /// SHA-256 of the manifest exactly as the server sent it, in hex
+    pub hash: String,
+}
+
+// Every chunk is AES-CTR encrypted with key, under a counter block of its own (see download_internals::decryptor)
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkEncryption {
+    /// 16, 24 or 32 bytes in hex, for AES-128, 192 or 256
+    pub key: String,
+    /// Hex, mixed into every chunk's counter block so no two contexts share a keystream
+    pub nonce: String,
+}
+
+impl DownloadContext {
+    pub fn max_files(&self) -> usize {
+        self.max_files.map(NonZeroUsize::get).unwrap_or(MAX_FILES_PER_BUCKET)
+    }
 }
 
 #[derive(Serialize)]
@@ -102,6 +736,9 @@ pub struct DownloadContext {
 pub struct ChunkBodyFile {
     filename: String,
     chunk_index: usize,
+    /// Only sent with the range capability, to skip the start of the chunk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offset: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -126,8 +763,33 @@ impl ChunkBody {
                 .map(|e| ChunkBodyFile {
                     filename: e.filename.clone(),
                     chunk_index: e.index,
+                    offset: (e.offset != 0).then_some(e.offset),
                 })
                 .collect(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_json(checksum: &str) -> String {
+        format!(r#"{{"permissions": 420, "ids": ["a"], "checksums": ["{checksum}"], "lengths": [4], "versionName": "1"}}"#)
+    }
+
+    #[test]
+    fn unique_manifest_keeps_identical_repeats() {
+        let json = format!(r#"{{"a": {0}, "b": {0}, "a": {0}}}"#, chunk_json("x"));
+        let manifest = serde_json::from_str::<UniqueManifest>(&json).unwrap().0;
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(manifest["a"].checksums, vec!["x"]);
+    }
+
+    #[test]
+    fn unique_manifest_refuses_conflicting_repeats() {
+        let json = format!(r#"{{"a": {}, "a": {}}}"#, chunk_json("x"), chunk_json("y"));
+        let e = serde_json::from_str::<UniqueManifest>(&json).err().unwrap();
+        assert!(e.to_string().contains("a is in the manifest more than once, with different contents"));
+    }
+}
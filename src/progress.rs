@@ -0,0 +1,106 @@
+use std::{
+    collections::VecDeque,
+    env,
+    io::{self, IsTerminal},
+    time::{Duration, Instant},
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::models::ProgressMode;
+
+// Styling goes through console (which indicatif draws with), so turning it off there covers everything.
+// NO_COLOR (https://no-color.org) only counts when it's set to something.
+pub fn configure_color(no_color: bool) {
+    let enabled = !no_color && env::var_os("NO_COLOR").is_none_or(|value| value.is_empty());
+    console::set_colors_enabled(enabled && io::stdout().is_terminal());
+    console::set_colors_enabled_stderr(enabled && io::stderr().is_terminal());
+}
+
+pub enum Progress {
+    /// Along with the bytes that were done before the download started
+    Bar(ProgressBar, usize),
+    Plain,
+    None,
+}
+
+impl Progress {
+    // completed is on top of total_size, and the bar starts from it
+    pub fn new(mode: ProgressMode, total_size: usize, completed: usize) -> Self {
+        let mode = match mode {
+            ProgressMode::Auto if io::stdout().is_terminal() => ProgressMode::Bar,
+            ProgressMode::Auto => ProgressMode::Plain,
+            mode => mode,
+        };
+
+        match mode {
+            ProgressMode::Bar => {
+                let bar = ProgressBar::new((total_size + completed) as u64).with_position(completed as u64);
+                bar.set_style(ProgressStyle::with_template("{wide_bar:.cyan/blue} {bytes}/{total_bytes} {binary_bytes_per_sec} {eta}").expect("failed to parse progress template"));
+                Progress::Bar(bar, completed)
+            }
+            ProgressMode::Plain => Progress::Plain,
+            ProgressMode::None | ProgressMode::Auto => Progress::None,
+        }
+    }
+
+    pub fn update(&self, downloaded: usize) {
+        if let Progress::Bar(bar, completed) = self {
+            bar.set_position((completed + downloaded) as u64);
+        }
+    }
+
+    // The per-bucket status line, which the bar already conveys
+    pub fn bucket_finished(&self, line: String) {
+        if let Progress::Plain = self {
+            println!("{line}");
+        }
+    }
+
+    // Messages have to go above the bar rather than through it
+    pub fn println(&self, line: String) {
+        match self {
+            Progress::Bar(bar, _) => bar.println(line),
+            _ => println!("{line}"),
+        }
+    }
+
+    pub fn finish(&self) {
+        if let Progress::Bar(bar, _) = self {
+            bar.finish();
+        }
+    }
+}
+
+// Long enough to even out bucket-sized bursts, short enough to follow real changes in speed
+const SPEED_WINDOW: Duration = Duration::from_secs(10);
+
+// Aggregate speed across all threads, over a rolling window rather than per bucket
+pub struct SpeedMeter {
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl SpeedMeter {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::from([(Instant::now(), 0)]),
+        }
+    }
+
+    // Takes the running total of bytes downloaded and returns bytes per second
+    pub fn sample(&mut self, downloaded: usize) -> f64 {
+        let now = Instant::now();
+        self.samples.push_back((now, downloaded));
+        // Always keep one sample from before the window to measure against
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) > SPEED_WINDOW {
+            self.samples.pop_front();
+        }
+
+        let (since, from) = self.samples.front().unwrap();
+        let elapsed = now.duration_since(*since).as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        downloaded.saturating_sub(*from) as f64 / elapsed
+    }
+}
@@ -0,0 +1,143 @@
+use std::fs;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::models::DownloadBucket;
+
+// What the download itself saw, filled in by download whether or not it succeeds
+#[derive(Default)]
+pub struct DownloadStats {
+    pub total_bytes: usize,
+    pub downloaded_bytes: usize,
+    pub failed_attempts: usize,
+    /// "<path> chunk <index>" for every chunk that didn't match its checksum when downloaded
+    pub checksum_mismatches: Vec<String>,
+    /// Manifest paths --keep-going carried on without, and any that failed their checksum along the way
+    pub failed_files: Vec<String>,
+    /// Bytes already on disk before the download started, set by the caller so progress counts them as done
+    pub complete_bytes: usize,
+}
+
+// Written at the end of every install with --report, to attach to support tickets rather than console output
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadReport {
+    pub game_id: String,
+    pub version: String,
+    pub install_dir: String,
+    /// Milliseconds since the unix epoch
+    pub started_at: i64,
+    pub finished_at: i64,
+    pub duration_secs: f64,
+    pub total_bytes: usize,
+    pub downloaded_bytes: usize,
+    /// Skipped, since they were already on disk
+    #[serde(default)]
+    pub complete_bytes: usize,
+    /// Bytes per second, over the whole run
+    pub average_speed: f64,
+    pub failed_attempts: usize,
+    pub checksum_mismatches: Vec<String>,
+    /// What --resume-from-report retries
+    #[serde(default)]
+    pub failed_files: Vec<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl DownloadReport {
+    pub fn new(params: &(String, String), install_dir: &str, started_at: i64, stats: DownloadStats, error: Option<String>) -> Self {
+        let finished_at = Utc::now().timestamp_millis();
+        let duration_secs = (finished_at - started_at) as f64 / 1000.0;
+        Self {
+            game_id: params.0.clone(),
+            version: params.1.clone(),
+            install_dir: install_dir.to_owned(),
+            started_at,
+            finished_at,
+            duration_secs,
+            total_bytes: stats.total_bytes,
+            downloaded_bytes: stats.downloaded_bytes,
+            complete_bytes: stats.complete_bytes,
+            average_speed: if duration_secs > 0.0 { stats.downloaded_bytes as f64 / duration_secs } else { 0.0 },
+            failed_attempts: stats.failed_attempts,
+            checksum_mismatches: stats.checksum_mismatches,
+            failed_files: stats.failed_files,
+            success: error.is_none(),
+            error,
+        }
+    }
+}
+
+// --dump-plan, the buckets as they'll be started, for checking how a manifest gets split up
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadPlan {
+    pub game_id: String,
+    pub buckets: Vec<PlannedBucket>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedBucket {
+    /// Position in the order buckets are started
+    pub index: usize,
+    pub version: String,
+    /// Sum of the drops' lengths
+    pub size: usize,
+    pub drops: Vec<PlannedDrop>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedDrop {
+    /// As it appears in the manifest
+    pub path: String,
+    pub chunk: usize,
+    /// Byte offset into the file
+    pub start: usize,
+    pub length: usize,
+    pub checksum: String,
+}
+
+impl DownloadPlan {
+    pub fn new(game_id: &str, buckets: &[DownloadBucket]) -> Self {
+        Self {
+            game_id: game_id.to_owned(),
+            buckets: buckets
+                .iter()
+                .enumerate()
+                .map(|(index, bucket)| PlannedBucket {
+                    index,
+                    version: bucket.version.clone(),
+                    size: bucket.drops.iter().map(|drop| drop.length).sum(),
+                    drops: bucket
+                        .drops
+                        .iter()
+                        .map(|drop| PlannedDrop {
+                            path: drop.filename.clone(),
+                            chunk: drop.index,
+                            start: drop.start,
+                            length: drop.length,
+                            checksum: drop.checksum.clone(),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn save_plan(path: &str, plan: &DownloadPlan) {
+    fs::write(path, serde_json::to_string_pretty(plan).expect("failed to serialize download plan")).expect("failed to save download plan");
+}
+
+pub fn save_report(path: &str, report: &DownloadReport) {
+    fs::write(path, serde_json::to_string_pretty(report).expect("failed to serialize download report")).expect("failed to save download report");
+}
+
+pub fn read_report(path: &str) -> Result<DownloadReport, anyhow::Error> {
+    Ok(serde_json::from_str::<DownloadReport>(&fs::read_to_string(path)?)?)
+}
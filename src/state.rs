@@ -0,0 +1,257 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs, io,
+    time::Duration,
+};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::BucketError,
+    models::{DownloadContext, DropManifest},
+    write_private,
+};
+
+// Bumped whenever a change to the format would break existing readers
+const STATE_SCHEMA_VERSION: u32 = 1;
+
+// The state file is a contract with launchers, so its layout should only ever grow
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallState {
+    pub schema_version: u32,
+    pub installs: Vec<InstallRecord>,
+    /// Download contexts kept between runs, so resuming doesn't have to mint new ones
+    #[serde(default)]
+    pub contexts: Vec<SavedContext>,
+    /// Downloads that were started but never finished, left behind by a crash or a failed run
+    #[serde(default)]
+    pub in_progress: Vec<InProgressDownload>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InProgressDownload {
+    pub game_id: String,
+    pub version: String,
+    pub install_dir: String,
+    /// Milliseconds since the unix epoch
+    pub started_at: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedContext {
+    pub game_id: String,
+    pub version: String,
+    /// Milliseconds since the unix epoch
+    pub created_at: i64,
+    pub context: DownloadContext,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct InstallRecord {
+    pub game_id: String,
+    pub version: String,
+    pub install_dir: String,
+    /// Chunk checksums per file path, as they were in the manifest that was installed
+    pub checksums: BTreeMap<String, Vec<String>>,
+    /// Targets of the symlinks in the manifest that was installed, which have no checksums to tell them apart
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub symlinks: BTreeMap<String, String>,
+    /// Mode of every file in the manifest that was installed, missing from records written before it was kept
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub permissions: BTreeMap<String, u32>,
+    /// Milliseconds since the unix epoch
+    pub completed_at: i64,
+    /// When `verify` last ran against this install, in milliseconds since the unix epoch
+    #[serde(default)]
+    pub verified_at: Option<i64>,
+    #[serde(default)]
+    pub verify_passed: Option<bool>,
+    /// The manifest the install was downloaded from, see ManifestLock
+    #[serde(default)]
+    pub manifest_hash: Option<String>,
+    /// The channel the version was picked from, for updates to follow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+}
+
+impl InstallRecord {
+    pub fn new(game_id: String, version: String, install_dir: String, manifest: &DropManifest, manifest_hash: Option<String>, channel: Option<String>) -> Self {
+        Self {
+            game_id,
+            version,
+            install_dir,
+            checksums: manifest.iter().map(|(path, chunk)| (path.clone(), chunk.checksums.clone())).collect(),
+            symlinks: manifest.iter().filter_map(|(path, chunk)| Some((path.clone(), chunk.symlink.clone()?))).collect(),
+            permissions: manifest.iter().map(|(path, chunk)| (path.clone(), chunk.permissions)).collect(),
+            completed_at: Utc::now().timestamp_millis(),
+            verified_at: None,
+            verify_passed: None,
+            manifest_hash,
+            channel,
+        }
+    }
+}
+
+impl InstallRecord {
+    // Everything in the manifest that isn't already installed with the same checksums, symlink target and mode. Older
+    // records have no targets, so their symlinks always count as changed, and no modes, which then aren't compared.
+    pub fn changed_files(&self, manifest: &DropManifest) -> DropManifest {
+        manifest
+            .iter()
+            .filter(|(path, chunk)| self.checksums.get(*path) != Some(&chunk.checksums) || self.symlinks.get(*path) != chunk.symlink.as_ref() || self.permissions.get(*path).is_some_and(|permissions| *permissions != chunk.permissions))
+            .map(|(path, chunk)| (path.clone(), chunk.clone()))
+            .collect()
+    }
+
+    // Chunks, as (path, index), whose checksum and every checksum before it in the file are the same as when this
+    // was installed. Those bytes are still where they were written, so they don't need downloading again.
+    pub fn installed_chunks(&self, manifest: &DropManifest) -> HashSet<(String, usize)> {
+        manifest
+            .iter()
+            .filter_map(|(path, chunk)| Some((path, chunk, self.checksums.get(path)?)))
+            .flat_map(|(path, chunk, installed)| {
+                chunk
+                    .checksums
+                    .iter()
+                    .zip(installed)
+                    .take_while(|(checksum, installed)| checksum == installed)
+                    .enumerate()
+                    .map(move |(index, _)| (path.clone(), index))
+            })
+            .collect()
+    }
+}
+
+impl InstallState {
+    pub fn find(&self, install_dir: &str) -> Option<&InstallRecord> {
+        self.installs.iter().find(|record| record.install_dir == install_dir)
+    }
+
+    pub fn find_mut(&mut self, install_dir: &str) -> Option<&mut InstallRecord> {
+        self.installs.iter_mut().find(|record| record.install_dir == install_dir)
+    }
+
+    // Marked as saved, so a download knows to replace it if the server has expired it since
+    pub fn saved_context(&self, game_id: &str, version: &str, max_age: Duration) -> Option<DownloadContext> {
+        let oldest = Utc::now().timestamp_millis() - max_age.as_millis() as i64;
+        self.contexts
+            .iter()
+            .find(|saved| saved.game_id == game_id && saved.version == version && saved.created_at >= oldest)
+            .map(|saved| DownloadContext { saved: true, ..saved.context.clone() })
+    }
+
+    // Contexts that come with a chunk key aren't kept, since the key would sit on disk for anyone to decrypt with
+    pub fn save_context(&mut self, game_id: &str, version: &str, context: &DownloadContext) {
+        if context.encryption.is_some() {
+            return;
+        }
+        self.contexts.retain(|saved| saved.game_id != game_id || saved.version != version);
+        self.contexts.push(SavedContext {
+            game_id: game_id.to_owned(),
+            version: version.to_owned(),
+            created_at: Utc::now().timestamp_millis(),
+            context: context.clone(),
+        });
+    }
+
+    // Past max_age they're never reused, so there's no point keeping them around
+    pub fn forget_old_contexts(&mut self, max_age: Duration) {
+        let oldest = Utc::now().timestamp_millis() - max_age.as_millis() as i64;
+        self.contexts.retain(|saved| saved.created_at >= oldest);
+    }
+
+    pub fn interrupted(&self, install_dir: &str) -> Option<&InProgressDownload> {
+        self.in_progress.iter().find(|download| download.install_dir == install_dir)
+    }
+
+    // Cleared by finish_download once the install verifies, so anything still here on startup was interrupted
+    pub fn start_download(&mut self, game_id: &str, version: &str, install_dir: &str) {
+        self.finish_download(install_dir);
+        self.in_progress.push(InProgressDownload {
+            game_id: game_id.to_owned(),
+            version: version.to_owned(),
+            install_dir: install_dir.to_owned(),
+            started_at: Utc::now().timestamp_millis(),
+        });
+    }
+
+    pub fn finish_download(&mut self, install_dir: &str) {
+        self.in_progress.retain(|download| download.install_dir != install_dir);
+    }
+
+    // There's only ever one install per directory, so a new record replaces the old one
+    pub fn record(&mut self, record: InstallRecord) {
+        self.installs.retain(|existing| existing.install_dir != record.install_dir);
+        self.installs.push(record);
+    }
+}
+
+pub fn read_install_state(path: &str) -> Result<InstallState, BucketError> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("failed to read state file {path}: {e}"));
+    if fs::exists(path).map_err(with_path)? {
+        let contents = fs::read_to_string(path).map_err(with_path)?;
+        let mut state = serde_json::from_str::<InstallState>(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("state file {path} is broken: {e}")))?;
+        // Older versions saved keys along with their contexts, and these go the next time the state is saved
+        state.contexts.retain(|saved| saved.context.encryption.is_none());
+        return Ok(state);
+    };
+
+    Ok(InstallState {
+        schema_version: STATE_SCHEMA_VERSION,
+        installs: Vec::new(),
+        contexts: Vec::new(),
+        in_progress: Vec::new(),
+    })
+}
+
+// Only readable by the owner on unix, as saved context tokens are as good as auth for downloading
+pub fn save_install_state(path: &str, state: &InstallState) -> Result<(), BucketError> {
+    write_private(path, &serde_json::to_string_pretty(state).expect("failed to serialize install state")).map_err(|e| io::Error::new(e.kind(), format!("failed to save state file {path}: {e}")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::DropChunk;
+
+    fn chunk(checksum: &str, symlink: Option<&str>, permissions: u32) -> DropChunk {
+        DropChunk {
+            permissions,
+            ids: Vec::new(),
+            checksums: if symlink.is_some() { Vec::new() } else { vec![checksum.to_owned()] },
+            lengths: Vec::new(),
+            version_name: "1".to_owned(),
+            symlink: symlink.map(str::to_owned),
+            mtime: None,
+        }
+    }
+
+    #[test]
+    fn changed_symlinks_and_modes() {
+        let installed = DropManifest::from([("bin".to_owned(), chunk("a", None, 0o755)), ("data".to_owned(), chunk("b", None, 0o644)), ("link".to_owned(), chunk("", Some("bin"), 0o777))]);
+        let record = InstallRecord::new("game".to_owned(), "1".to_owned(), "./game".to_owned(), &installed, None, None);
+        assert!(record.changed_files(&installed).is_empty());
+
+        let manifest = DropManifest::from([("bin".to_owned(), chunk("a", None, 0o644)), ("data".to_owned(), chunk("b", None, 0o644)), ("link".to_owned(), chunk("", Some("data"), 0o777))]);
+        let mut changed = record.changed_files(&manifest).into_keys().collect::<Vec<_>>();
+        changed.sort();
+        assert_eq!(changed, ["bin", "link"]);
+    }
+
+    #[test]
+    fn changed_files_from_older_record() {
+        let installed = DropManifest::from([("bin".to_owned(), chunk("a", None, 0o755)), ("link".to_owned(), chunk("", Some("bin"), 0o777))]);
+        let mut record = InstallRecord::new("game".to_owned(), "1".to_owned(), "./game".to_owned(), &installed, None, None);
+        record.symlinks.clear();
+        record.permissions.clear();
+
+        let manifest = DropManifest::from([("bin".to_owned(), chunk("a", None, 0o644)), ("link".to_owned(), chunk("", Some("bin"), 0o777))]);
+        assert_eq!(record.changed_files(&manifest).into_keys().collect::<Vec<_>>(), ["link"]);
+    }
+}
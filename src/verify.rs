@@ -0,0 +1,292 @@
+use std::{collections::HashMap, fs, path::Path, time::UNIX_EPOCH};
+
+use ring::digest::{Context, SHA256};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    download::file_drops,
+    download_internals::{checksum_matches, drop_digest},
+    models::DropManifest,
+};
+
+// Files whose size and mtime haven't moved since they last verified aren't hashed again
+#[derive(Serialize, Deserialize, Default)]
+pub struct VerifyCache {
+    entries: HashMap<String, CacheEntry>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    size: u64,
+    mtime_nanos: u64,
+    checksums: Vec<String>,
+}
+
+impl VerifyCache {
+    // For files written with a fixed mtime, which a corrupt rewrite of the same size would share with the good copy
+    pub fn forget(&mut self, install_dir: &str, manifest: &DropManifest) {
+        let base_path = Path::new(install_dir);
+        for raw_path in manifest.keys() {
+            self.entries.remove(&*base_path.join(Path::new(raw_path)).to_string_lossy());
+        }
+    }
+}
+
+pub fn read_verify_cache(path: &str) -> VerifyCache {
+    if fs::exists(path).expect("failed to check for verify cache") {
+        let contents = fs::read_to_string(path).expect("failed to read verify cache");
+        // It's only a cache, so a broken one just means hashing everything again
+        return serde_json::from_str::<VerifyCache>(&contents).unwrap_or_default();
+    };
+
+    VerifyCache::default()
+}
+
+// Everything known about why a file failed, for working out what went wrong with it
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyFailure {
+    pub path: String,
+    pub expected_size: u64,
+    /// None when the file is missing
+    pub actual_size: Option<u64>,
+    /// Only the chunks that didn't match
+    pub chunks: Vec<ChunkMismatch>,
+    /// Set for symlinks, which have no size or chunks to compare
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_target: Option<String>,
+    /// Permission bits, only set when checking permissions found them different
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_mode: Option<u32>,
+}
+
+impl VerifyFailure {
+    // Whether the content is fine, and only the permissions are wrong
+    pub fn mode_only(&self) -> bool {
+        self.expected_mode.is_some() && self.expected_target.is_none() && self.actual_size == Some(self.expected_size) && self.chunks.is_empty()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkMismatch {
+    pub index: usize,
+    pub expected: String,
+    /// None when the file ends before the chunk does, or it couldn't be read
+    pub actual: Option<String>,
+}
+
+pub fn save_verify_cache(path: &str, cache: &VerifyCache) {
+    fs::write(path, serde_json::to_string(cache).expect("failed to serialize verify cache")).expect("failed to save verify cache");
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+// Windows has no mode bits to compare
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+// Sets the manifest's permissions on every failure that had the wrong ones, returning the failures that are left
+// (those with content problems). The mode is left alone on anything a content repair will rewrite anyway.
+pub fn repair_permissions(install_dir: &str, failed: Vec<VerifyFailure>) -> Vec<VerifyFailure> {
+    failed
+        .into_iter()
+        .filter_map(|failure| {
+            if !failure.mode_only() {
+                return Some(failure);
+            }
+            let path = Path::new(install_dir).join(&failure.path);
+            match set_mode(&path, failure.expected_mode.unwrap()) {
+                Ok(()) => {
+                    println!("{}: fixed mode {:o} to {:o}", failure.path, failure.actual_mode.unwrap_or(0), failure.expected_mode.unwrap());
+                    None
+                }
+                Err(e) => {
+                    println!("{}: failed to fix mode: {e}", failure.path);
+                    Some(failure)
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Returns the files that failed verification, sorted by path. With check_permissions, a file whose mode doesn't
+// match the manifest's fails too, even when its content is fine.
+pub fn verify(install_dir: &str, manifest: &DropManifest, cache: &mut VerifyCache, check_permissions: bool) -> Vec<VerifyFailure> {
+    let base_path = Path::new(install_dir);
+
+    let mut raw_paths = manifest.keys().collect::<Vec<&String>>();
+    raw_paths.sort();
+
+    let mut failed = Vec::new();
+    for raw_path in raw_paths {
+        let chunk = &manifest[raw_path];
+        let path = base_path.join(Path::new(raw_path));
+        let key = path.to_string_lossy().to_string();
+        let mut failure = VerifyFailure {
+            path: raw_path.clone(),
+            expected_size: chunk.lengths.iter().sum::<usize>() as u64,
+            actual_size: None,
+            chunks: Vec::new(),
+            expected_target: None,
+            actual_target: None,
+            expected_mode: None,
+            actual_mode: None,
+        };
+
+        if let Some(target) = &chunk.symlink {
+            let actual_target = fs::read_link(&path).ok();
+            if actual_target.as_deref() != Some(Path::new(target)) {
+                failed.push(VerifyFailure {
+                    expected_size: 0,
+                    expected_target: Some(target.clone()),
+                    actual_target: actual_target.map(|target| target.to_string_lossy().to_string()),
+                    ..failure
+                });
+            }
+            continue;
+        }
+
+        let Ok(metadata) = fs::metadata(&path) else {
+            cache.entries.remove(&key);
+            failed.push(failure);
+            continue;
+        };
+        // Checked apart from the content, since the cache only knows about content
+        let expected_mode = chunk.permissions & 0o7777;
+        if check_permissions && let Some(actual_mode) = file_mode(&metadata).filter(|mode| *mode != expected_mode) {
+            failure.expected_mode = Some(expected_mode);
+            failure.actual_mode = Some(actual_mode);
+        }
+
+        let entry = CacheEntry {
+            size: metadata.len(),
+            mtime_nanos: metadata.modified().ok().and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok()).map(|mtime| mtime.as_nanos() as u64).unwrap_or(0),
+            checksums: chunk.checksums.clone(),
+        };
+        failure.actual_size = Some(metadata.len());
+        if cache.entries.get(&key) == Some(&entry) {
+            if failure.expected_mode.is_some() {
+                failed.push(failure);
+            }
+            continue;
+        }
+
+        // Every chunk is hashed, even after one fails, so the report covers the whole file
+        failure.chunks = file_drops(raw_path, path, chunk)
+            .iter()
+            .filter_map(|drop| {
+                let actual = drop_digest(drop).ok().flatten();
+                match actual {
+                    Some(digest) if checksum_matches(&digest, &drop.checksum).unwrap_or(false) => None,
+                    actual => Some(ChunkMismatch {
+                        index: drop.index,
+                        expected: drop.checksum.clone(),
+                        actual: actual.map(|digest| hex::encode(*digest)),
+                    }),
+                }
+            })
+            .collect();
+
+        if failure.actual_size == Some(failure.expected_size) && failure.chunks.is_empty() {
+            cache.entries.insert(key, entry);
+            if failure.expected_mode.is_some() {
+                failed.push(failure);
+            }
+        } else {
+            cache.entries.remove(&key);
+            failed.push(failure);
+        }
+    }
+
+    failed
+}
+
+// SHA-256 over every manifest entry, sorted by path, each written as the path, a NUL, then its chunk checksums
+// in lowercase hex joined by commas (or "-> target" for a symlink), then a newline. Only the manifest goes in, so
+// two installs of the same version always match, and any change to a file's content changes the digest.
+pub fn install_digest(manifest: &DropManifest) -> String {
+    let mut raw_paths = manifest.keys().collect::<Vec<&String>>();
+    raw_paths.sort();
+
+    let mut context = Context::new(&SHA256);
+    for raw_path in raw_paths {
+        let chunk = &manifest[raw_path];
+        context.update(raw_path.as_bytes());
+        context.update(b"\0");
+        match &chunk.symlink {
+            Some(target) => context.update(format!("-> {target}").as_bytes()),
+            None => context.update(chunk.checksums.iter().map(|checksum| checksum.to_lowercase()).collect::<Vec<_>>().join(",").as_bytes()),
+        }
+        context.update(b"\n");
+    }
+
+    hex::encode(context.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use filetime::{FileTime, set_file_mtime};
+
+    use super::*;
+    use crate::models::DropChunk;
+
+    #[test]
+    fn corrupt_rewrite_with_fixed_mtime() {
+        let dir = env::temp_dir().join(format!("bucket-test-{}-verify-mtime", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let install_dir = dir.to_string_lossy().to_string();
+        let manifest = DropManifest::from([(
+            "a".to_owned(),
+            DropChunk {
+                permissions: 0o644,
+                ids: vec![String::new()],
+                checksums: vec![format!("{:x}", md5::compute(b"hello"))],
+                lengths: vec![5],
+                version_name: "1".to_owned(),
+                symlink: None,
+                mtime: None,
+            },
+        )]);
+        // What --set-mtime stamps every file with, whatever was written
+        let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+
+        fs::write(dir.join("a"), "hello").unwrap();
+        set_file_mtime(dir.join("a"), mtime).unwrap();
+        let mut cache = VerifyCache::default();
+        assert!(verify(&install_dir, &manifest, &mut cache, false).is_empty());
+
+        fs::write(dir.join("a"), "jello").unwrap();
+        set_file_mtime(dir.join("a"), mtime).unwrap();
+        cache.forget(&install_dir, &manifest);
+        let failed = verify(&install_dir, &manifest, &mut cache, false);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].chunks.len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}